@@ -0,0 +1,3 @@
+-- Add migration script here
+
+ALTER TABLE peers ADD COLUMN pk BYTEA NOT NULL DEFAULT '';
@@ -0,0 +1,8 @@
+-- Add migration script here
+
+-- Timestamps are seconds since the Unix epoch, info is an opaque JSON document.
+ALTER TABLE peers ADD COLUMN created_at BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE peers ADD COLUMN last_seen_at BIGINT;
+ALTER TABLE peers ADD COLUMN last_reg_ip VARCHAR(50);
+ALTER TABLE peers ADD COLUMN serial INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE peers ADD COLUMN info TEXT NOT NULL DEFAULT '{}';
@@ -1,68 +1,224 @@
-use std::{net::SocketAddr, pin::Pin};
+use std::{
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use hbb_common::{ResultType, bytes::Bytes};
-use sqlx::{PgPool, Pool, Postgres, Transaction, migrate::MigrateError};
-use tracing_subscriber::registry::Data;
+use hbb_common::bytes::Bytes;
+use sqlx::{
+    AnyPool,
+    any::install_default_drivers,
+    migrate::{Migrate, Migrator},
+};
 
 use crate::{
-    error::{TangoError, TangoResult},
-    rustdesk::{peer::Peer, peer_id::PeerId},
+    error::{PeerError, TangoError, TangoResult},
+    rustdesk::{
+        peer::{Peer, PeerInfo, normalize_addr},
+        peer_id::PeerId,
+    },
 };
 
+#[cfg(test)]
+mod tests;
+
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Postgres and SQLite both go through sqlx's `Any` driver, the URL scheme
+/// picks the backend. The queries stick to SQL both understand, `$N`
+/// placeholders included.
 #[derive(Clone)]
 pub struct Database {
-    pool: PgPool,
+    pool: AnyPool,
 }
 
 impl Database {
     pub async fn new(database_url: &str) -> TangoResult<Self> {
-        let pool = PgPool::connect_lazy(database_url)?;
+        let supported = ["postgres://", "postgresql://", "sqlite:"];
+        if !supported
+            .iter()
+            .any(|scheme| database_url.starts_with(scheme))
+        {
+            return Err(TangoError::InvalidConfig(
+                "database_url has to be a postgres:// or sqlite: URL",
+            ));
+        }
+
+        install_default_drivers();
+
+        // Create the SQLite file on first start, like hbbs does.
+        let mut database_url = database_url.to_owned();
+        if database_url.starts_with("sqlite:") && !database_url.contains("mode=") {
+            database_url.push(if database_url.contains('?') { '&' } else { '?' });
+            database_url.push_str("mode=rwc");
+        }
+
+        let pool = AnyPool::connect_lazy(&database_url)?;
 
         Ok(Database { pool })
     }
 
     pub async fn migrate(&self) -> TangoResult<()> {
-        sqlx::migrate!()
+        MIGRATOR
             .run(&self.pool)
             .await
             .map_err(TangoError::Migration)
     }
 
-    pub async fn close(self) {
+    /// Fails if the database was migrated by a newer tango, running against
+    /// a schema we don't know could corrupt it.
+    pub async fn check_schema(&self) -> TangoResult<()> {
+        let mut conn = self.pool.acquire().await?;
+        conn.ensure_migrations_table().await?;
+
+        let known = MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0);
+        let applied = conn.list_applied_migrations().await?;
+
+        if let Some(schema) = applied.iter().map(|m| m.version).max()
+            && schema > known
+        {
+            return Err(TangoError::SchemaTooNew { schema, known });
+        }
+
+        Ok(())
+    }
+
+    pub async fn close(&self) {
         self.pool.close().await;
     }
 
     pub async fn create_peer(&self, peer: Peer) -> TangoResult<()> {
-        sqlx::query!(
-            "INSERT INTO peers (peer_id, address, uuid) VALUES ($1, $2, $3)",
-            peer.socket_address.to_string(),
-            peer.rd_id,
-            peer.device_uuid.as_ref()
+        let addr = normalize_addr(peer.socket_address);
+        let now = unix_now();
+
+        sqlx::query(
+            "INSERT INTO peers
+                (peer_id, address, uuid, pk, created_at, last_seen_at, last_reg_ip, serial, info)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
         )
+        .bind(peer.peer_id.to_string())
+        .bind(addr.to_string())
+        .bind(peer.device_uuid.as_ref())
+        .bind(peer.pk.as_ref())
+        .bind(now)
+        .bind(now)
+        .bind(addr.ip().to_string())
+        .bind(peer.serial)
+        .bind(serde_json::to_string(&peer.info)?)
         .execute(&self.pool)
-        .await?;
+        .await
+        .map_err(insert_error)?;
+
         Ok(())
     }
 
     pub async fn select_peer_by_id(&self, id: PeerId) -> TangoResult<Option<Peer>> {
-        match sqlx::query!(
-            "SELECT peer_id, address, uuid FROM peers WHERE peer_id = $1",
-            id.to_string()
+        sqlx::query_as::<_, PeerRow>(
+            "SELECT peer_id, address, uuid, pk, serial, info FROM peers WHERE peer_id = $1",
         )
+        .bind(id.to_string())
         .fetch_optional(&self.pool)
         .await?
-        {
-            Some(peer) => Ok(Some(Peer {
-                socket_address: peer.address.parse()?,
-                peer_id: peer.peer_id.try_into()?,
-                device_uuid: peer.uuid.into(),
-            })),
-            None => Ok(None),
+        .map(Peer::try_from)
+        .transpose()
+    }
+
+    pub async fn select_peer_meta(&self, id: PeerId) -> TangoResult<Option<PeerMeta>> {
+        Ok(sqlx::query_as::<_, PeerMeta>(
+            "SELECT created_at, last_seen_at, last_reg_ip FROM peers WHERE peer_id = $1",
+        )
+        .bind(id.to_string())
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    /// Records a heartbeat from `addr`.
+    pub async fn update_peer_seen(
+        &self,
+        id: PeerId,
+        addr: SocketAddr,
+        serial: i32,
+    ) -> TangoResult<()> {
+        let addr = normalize_addr(addr);
+
+        sqlx::query(
+            "UPDATE peers SET address = $1, last_reg_ip = $2, last_seen_at = $3, serial = $4
+            WHERE peer_id = $5",
+        )
+        .bind(addr.to_string())
+        .bind(addr.ip().to_string())
+        .bind(unix_now())
+        .bind(serial)
+        .bind(id.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn update_peer_pk(&self, id: PeerId, addr: SocketAddr, pk: Bytes) -> TangoResult<()> {
+        let addr = normalize_addr(addr);
+
+        sqlx::query(
+            "UPDATE peers SET address = $1, pk = $2, last_reg_ip = $3, last_seen_at = $4
+            WHERE peer_id = $5",
+        )
+        .bind(addr.to_string())
+        .bind(pk.as_ref())
+        .bind(addr.ip().to_string())
+        .bind(unix_now())
+        .bind(id.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn update_peer_info(&self, id: PeerId, info: &PeerInfo) -> TangoResult<()> {
+        sqlx::query("UPDATE peers SET info = $1 WHERE peer_id = $2")
+            .bind(serde_json::to_string(info)?)
+            .bind(id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Moves a registration to a new id in a single statement, so a failure
+    /// can't leave the device without one.
+    pub async fn rename_peer(
+        &self,
+        old_id: PeerId,
+        id: PeerId,
+        addr: SocketAddr,
+        pk: Bytes,
+    ) -> TangoResult<()> {
+        let addr = normalize_addr(addr);
+
+        let res = sqlx::query(
+            "UPDATE peers SET peer_id = $1, address = $2, pk = $3, last_reg_ip = $4,
+                last_seen_at = $5
+            WHERE peer_id = $6",
+        )
+        .bind(id.to_string())
+        .bind(addr.to_string())
+        .bind(pk.as_ref())
+        .bind(addr.ip().to_string())
+        .bind(unix_now())
+        .bind(old_id.to_string())
+        .execute(&self.pool)
+        .await
+        .map_err(insert_error)?;
+
+        if res.rows_affected() == 0 {
+            return Err(TangoError::PeerError(PeerError::DoesntExist));
         }
+
+        Ok(())
     }
 
     pub async fn remove_peer_by_uuid(&self, uuid: Bytes) -> TangoResult<()> {
-        sqlx::query!("DELETE FROM peers WHERE uuid = $1", uuid.as_ref())
+        sqlx::query("DELETE FROM peers WHERE uuid = $1")
+            .bind(uuid.as_ref())
             .execute(&self.pool)
             .await?;
 
@@ -73,3 +229,56 @@ impl Database {
         self.remove_peer_by_uuid(peer.device_uuid).await
     }
 }
+
+/// Bookkeeping kept next to a registration, timestamps are seconds since
+/// the Unix epoch.
+#[derive(sqlx::FromRow, Debug)]
+pub struct PeerMeta {
+    pub created_at: i64,
+    pub last_seen_at: Option<i64>,
+    pub last_reg_ip: Option<String>,
+}
+
+/// Row layout of the `peers` columns tango reads back.
+#[derive(sqlx::FromRow)]
+struct PeerRow {
+    peer_id: String,
+    address: String,
+    uuid: Vec<u8>,
+    pk: Vec<u8>,
+    serial: i32,
+    info: String,
+}
+
+impl TryFrom<PeerRow> for Peer {
+    type Error = TangoError;
+
+    fn try_from(row: PeerRow) -> Result<Self, Self::Error> {
+        Ok(Peer {
+            socket_address: normalize_addr(row.address.parse()?),
+            peer_id: row.peer_id.try_into().map_err(PeerError::IDError)?,
+            device_uuid: row.uuid.into(),
+            pk: row.pk.into(),
+            serial: row.serial,
+            // Written by us, but a broken document shouldn't lock the peer out.
+            info: serde_json::from_str(&row.info).unwrap_or_default(),
+            ..Default::default()
+        })
+    }
+}
+
+fn insert_error(err: sqlx::Error) -> TangoError {
+    match err {
+        sqlx::Error::Database(e) if e.is_unique_violation() => {
+            TangoError::PeerError(PeerError::AlreadyExists)
+        }
+        err => TangoError::Db(err),
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
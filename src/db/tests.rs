@@ -0,0 +1,305 @@
+use hbb_common::bytes::Bytes;
+
+use super::Database;
+use crate::{
+    error::{PeerError, TangoError},
+    rustdesk::{
+        peer::{Peer, PeerInfo},
+        peer_id::PeerId,
+    },
+};
+
+fn peer(id: &str, uuid: &[u8]) -> Peer {
+    Peer {
+        socket_address: "1.2.3.4:21116".parse().unwrap(),
+        peer_id: PeerId::new(id).unwrap(),
+        device_uuid: Bytes::copy_from_slice(uuid),
+        pk: Bytes::from_static(b"public key"),
+        serial: 3,
+        info: PeerInfo {
+            hostname: "workstation".into(),
+            username: "alice".into(),
+            platform: "Linux".into(),
+        },
+        ..Default::default()
+    }
+}
+
+/// Runs each test against a fresh, migrated database for every backend.
+macro_rules! storage_tests {
+    ($($name:ident),* $(,)?) => {
+        mod postgres {
+            use sqlx::{
+                ConnectOptions,
+                postgres::{PgConnectOptions, PgPoolOptions},
+            };
+
+            $(
+                #[sqlx::test]
+                async fn $name(_: PgPoolOptions, options: PgConnectOptions) {
+                    let db = super::connect(options.to_url_lossy().as_str()).await;
+                    super::$name(db).await;
+                }
+            )*
+        }
+
+        mod sqlite {
+            use sqlx::{
+                ConnectOptions,
+                sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+            };
+
+            $(
+                #[sqlx::test]
+                async fn $name(_: SqlitePoolOptions, options: SqliteConnectOptions) {
+                    let db = super::connect(options.to_url_lossy().as_str()).await;
+                    super::$name(db).await;
+                }
+            )*
+        }
+    };
+}
+
+storage_tests!(
+    create_and_select,
+    select_missing_peer,
+    newer_schema_is_refused,
+    peer_meta,
+    peer_meta_follows_updates,
+    mapped_address_is_stored_normalised,
+    duplicate_id_already_exists,
+    duplicate_uuid_already_exists,
+    remove_by_uuid,
+    remove_peer,
+    update_seen,
+    update_pk,
+    update_info,
+    rename,
+    rename_to_existing_id,
+);
+
+async fn connect(database_url: &str) -> Database {
+    Database::new(database_url).await.unwrap()
+}
+
+async fn select(db: &Database, id: &str) -> Option<Peer> {
+    db.select_peer_by_id(PeerId::new(id).unwrap())
+        .await
+        .unwrap()
+}
+
+async fn create_and_select(db: Database) {
+    db.create_peer(peer("123456789", b"uuid-1")).await.unwrap();
+
+    let stored = select(&db, "123456789").await.unwrap();
+    assert_eq!(stored.peer_id.to_string(), "123456789");
+    assert_eq!(stored.socket_address, "1.2.3.4:21116".parse().unwrap());
+    assert_eq!(stored.device_uuid.as_ref(), b"uuid-1");
+    assert_eq!(stored.pk.as_ref(), b"public key");
+    assert_eq!(stored.serial, 3);
+    assert_eq!(stored.info.hostname, "workstation");
+    assert_eq!(stored.info.username, "alice");
+    assert_eq!(stored.info.platform, "Linux");
+}
+
+async fn select_missing_peer(db: Database) {
+    assert!(select(&db, "123456789").await.is_none());
+}
+
+async fn newer_schema_is_refused(db: Database) {
+    db.check_schema().await.unwrap();
+
+    let newest = super::MIGRATOR.iter().map(|m| m.version).max().unwrap();
+    sqlx::query(
+        "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+        VALUES ($1, 'from a newer tango', TRUE, $2, 0)",
+    )
+    .bind(newest + 1)
+    .bind(vec![0u8; 48])
+    .execute(&db.pool)
+    .await
+    .unwrap();
+
+    let err = db.check_schema().await.unwrap_err();
+    assert!(matches!(err, TangoError::SchemaTooNew { schema, known }
+        if schema == newest + 1 && known == newest));
+}
+
+async fn peer_meta(db: Database) {
+    db.create_peer(peer("123456789", b"uuid-1")).await.unwrap();
+
+    let meta = db
+        .select_peer_meta(PeerId::new("123456789").unwrap())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(meta.created_at > 0);
+    assert_eq!(meta.last_seen_at, Some(meta.created_at));
+    assert_eq!(meta.last_reg_ip.as_deref(), Some("1.2.3.4"));
+
+    let missing = db
+        .select_peer_meta(PeerId::new("987654321").unwrap())
+        .await
+        .unwrap();
+    assert!(missing.is_none());
+}
+
+async fn peer_meta_follows_updates(db: Database) {
+    db.create_peer(peer("123456789", b"uuid-1")).await.unwrap();
+
+    let id = PeerId::new("123456789").unwrap();
+    db.update_peer_seen(id.clone(), "[::ffff:5.6.7.8]:4000".parse().unwrap(), 4)
+        .await
+        .unwrap();
+
+    let meta = db.select_peer_meta(id).await.unwrap().unwrap();
+    assert_eq!(meta.last_reg_ip.as_deref(), Some("5.6.7.8"));
+    assert!(meta.last_seen_at.unwrap() >= meta.created_at);
+}
+
+async fn mapped_address_is_stored_normalised(db: Database) {
+    let mut p = peer("123456789", b"uuid-1");
+    p.socket_address = "[::ffff:1.2.3.4]:21116".parse().unwrap();
+    db.create_peer(p).await.unwrap();
+
+    let stored = select(&db, "123456789").await.unwrap();
+    assert_eq!(stored.socket_address, "1.2.3.4:21116".parse().unwrap());
+}
+
+async fn duplicate_id_already_exists(db: Database) {
+    db.create_peer(peer("123456789", b"uuid-1")).await.unwrap();
+
+    let err = db
+        .create_peer(peer("123456789", b"uuid-2"))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        TangoError::PeerError(PeerError::AlreadyExists)
+    ));
+}
+
+async fn duplicate_uuid_already_exists(db: Database) {
+    db.create_peer(peer("123456789", b"uuid-1")).await.unwrap();
+
+    let err = db
+        .create_peer(peer("987654321", b"uuid-1"))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        TangoError::PeerError(PeerError::AlreadyExists)
+    ));
+}
+
+async fn remove_by_uuid(db: Database) {
+    db.create_peer(peer("123456789", b"uuid-1")).await.unwrap();
+    db.create_peer(peer("987654321", b"uuid-2")).await.unwrap();
+
+    db.remove_peer_by_uuid(Bytes::from_static(b"uuid-1"))
+        .await
+        .unwrap();
+
+    assert!(select(&db, "123456789").await.is_none());
+    assert!(select(&db, "987654321").await.is_some());
+}
+
+async fn remove_peer(db: Database) {
+    db.create_peer(peer("123456789", b"uuid-1")).await.unwrap();
+
+    db.remove_peer(peer("123456789", b"uuid-1")).await.unwrap();
+
+    assert!(select(&db, "123456789").await.is_none());
+}
+
+async fn update_seen(db: Database) {
+    db.create_peer(peer("123456789", b"uuid-1")).await.unwrap();
+
+    let id = PeerId::new("123456789").unwrap();
+    db.update_peer_seen(id, "5.6.7.8:4000".parse().unwrap(), 4)
+        .await
+        .unwrap();
+
+    let stored = select(&db, "123456789").await.unwrap();
+    assert_eq!(stored.socket_address, "5.6.7.8:4000".parse().unwrap());
+    assert_eq!(stored.serial, 4);
+    assert_eq!(stored.pk.as_ref(), b"public key");
+}
+
+async fn update_pk(db: Database) {
+    db.create_peer(peer("123456789", b"uuid-1")).await.unwrap();
+
+    let id = PeerId::new("123456789").unwrap();
+    db.update_peer_pk(
+        id,
+        "5.6.7.8:4000".parse().unwrap(),
+        Bytes::from_static(b"new key"),
+    )
+    .await
+    .unwrap();
+
+    let stored = select(&db, "123456789").await.unwrap();
+    assert_eq!(stored.socket_address, "5.6.7.8:4000".parse().unwrap());
+    assert_eq!(stored.pk.as_ref(), b"new key");
+}
+
+async fn update_info(db: Database) {
+    db.create_peer(peer("123456789", b"uuid-1")).await.unwrap();
+
+    let info = PeerInfo {
+        hostname: "laptop".into(),
+        username: "bob".into(),
+        platform: "Windows".into(),
+    };
+    db.update_peer_info(PeerId::new("123456789").unwrap(), &info)
+        .await
+        .unwrap();
+
+    let stored = select(&db, "123456789").await.unwrap();
+    assert_eq!(stored.info.hostname, "laptop");
+    assert_eq!(stored.info.username, "bob");
+    assert_eq!(stored.info.platform, "Windows");
+}
+
+async fn rename(db: Database) {
+    db.create_peer(peer("123456789", b"uuid-1")).await.unwrap();
+
+    let (old_id, id) = (
+        PeerId::new("123456789").unwrap(),
+        PeerId::new("new-id").unwrap(),
+    );
+    db.rename_peer(
+        old_id,
+        id,
+        "5.6.7.8:4000".parse().unwrap(),
+        Bytes::from_static(b"new key"),
+    )
+    .await
+    .unwrap();
+
+    assert!(select(&db, "123456789").await.is_none());
+    let stored = select(&db, "new-id").await.unwrap();
+    assert_eq!(stored.device_uuid.as_ref(), b"uuid-1");
+    assert_eq!(stored.pk.as_ref(), b"new key");
+    assert_eq!(stored.info.hostname, "workstation");
+}
+
+async fn rename_to_existing_id(db: Database) {
+    db.create_peer(peer("123456789", b"uuid-1")).await.unwrap();
+    db.create_peer(peer("987654321", b"uuid-2")).await.unwrap();
+
+    let (old_id, id) = (
+        PeerId::new("123456789").unwrap(),
+        PeerId::new("987654321").unwrap(),
+    );
+    let err = db
+        .rename_peer(old_id, id, "5.6.7.8:4000".parse().unwrap(), Bytes::new())
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        TangoError::PeerError(PeerError::AlreadyExists)
+    ));
+
+    assert!(select(&db, "123456789").await.is_some());
+}
@@ -1,4 +1,7 @@
-use std::{error::Error, net::AddrParseError};
+use std::{
+    error::Error,
+    net::{AddrParseError, SocketAddr},
+};
 
 use askama::Template;
 use axum::{
@@ -21,6 +24,15 @@ pub enum TangoError {
     #[error("Database migration error")]
     Migration(#[from] MigrateError),
 
+    #[error("Database schema version {schema} is newer than the newest known one ({known})")]
+    SchemaTooNew { schema: i64, known: i64 },
+
+    #[error("Unknown command: {0}")]
+    UnknownCommand(String),
+
+    #[error("Missing argument: {0}")]
+    MissingArgument(&'static str),
+
     #[error("Unauthorized")]
     Unauthorized,
 
@@ -39,6 +51,18 @@ pub enum TangoError {
     #[error("Error loading config")]
     Config(#[from] confy::ConfyError),
 
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(&'static str),
+
+    #[error("RustDesk networking error")]
+    Hbb(#[from] hbb_common::anyhow::Error),
+
+    #[error("Server key error: {0}")]
+    ServerKey(&'static str),
+
+    #[error("Error accessing the server key file")]
+    KeyFile(#[source] std::io::Error),
+
     #[error("Error running async task")]
     Join(#[from] JoinError),
 
@@ -51,8 +75,17 @@ pub enum TangoError {
 	#[error("I/O Error")]
 	IOError(IOError),
 
-	#[error("Rendezvous protocol error")]
-	RendezvousError,
+	#[error("Unexpected {kind} rendezvous message from {addr}")]
+	RendezvousError { kind: &'static str, addr: SocketAddr },
+
+    #[error("Invalid socket address in a rendezvous message")]
+    InvalidSocketAddr,
+
+    #[error("Error encoding JSON")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Error encoding a protobuf message")]
+    Protobuf(#[from] hbb_common::protobuf::Error),
 
 	#[error("Doesn't exist")]
 	DoesntExist
@@ -68,15 +101,30 @@ pub enum PeerError {
 
 	#[error("ID Error")]
 	IDError(#[from] PeerIdError),
-
-    #[error("Register PK error")]
-    RegisterPk,
 }
 
 #[derive(Debug, Error)]
 pub enum IOError {
 	#[error("I/O Error in the main listener")]
-    MainListener
+    MainListener,
+
+    #[error("I/O Error in the NAT test listener")]
+    NatListener,
+
+    #[error("I/O Error in the WebSocket listener")]
+    WsListener,
+
+    #[error("I/O Error in the rendezvous UDP socket")]
+    UdpSocket,
+
+    #[error("I/O Error in the LAN discovery socket")]
+    DiscoverySocket,
+
+    #[error("I/O Error in the relay listener")]
+    RelayListener,
+
+    #[error("I/O Error in the relay WebSocket listener")]
+    RelayWsListener,
 }
 
 
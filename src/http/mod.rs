@@ -1,13 +1,21 @@
 use std::sync::Arc;
 
-use axum::{Router, routing::{get, get_service}, serve};
+use axum::{Router, routing::{get, get_service, post}, serve};
 use tokio::net::TcpListener;
 use tower::ServiceBuilder;
 use tower_http::services::ServeDir;
-use routes::webui::login::login;
+use routes::{
+    api::sysinfo::sysinfo,
+    webui::{index::index, login::login},
+};
 use tracing::{info};
 
-use crate::{conf::TangoConfig, db::Database, error::TangoError};
+use crate::{
+    conf::TangoConfig,
+    db::Database,
+    error::TangoError,
+    rustdesk::{key::ServerKey, peer::PeersCollection},
+};
 
 pub mod routes;
 pub mod webui;
@@ -16,6 +24,8 @@ pub mod webui;
 pub struct HTTPState {
     pub db: Database,
     pub config: TangoConfig,
+    pub server_key: ServerKey,
+    pub peers: PeersCollection,
 }
 
 pub async fn start_http_server(addr: String, state: HTTPState) -> Result<(), TangoError> {
@@ -26,9 +36,27 @@ pub async fn start_http_server(addr: String, state: HTTPState) -> Result<(), Tan
 	};
 
 	let routes = Router::new()
+		.route("/", get(index))
 		.route("/login", get(login))
+		.route("/api/sysinfo", post(sysinfo))
 		.nest_service("/static", ServeDir::new("./static"))
 		.with_state(Arc::new(state));
 
 	axum::serve(listener, routes).await.map_err(TangoError::HttpServer)
 }
+
+/// Serves the endpoints RustDesk clients call themselves, they can't reach
+/// the web UI listener which usually only binds to localhost.
+pub async fn start_api_server(addr: String, state: HTTPState) -> Result<(), TangoError> {
+	let listener = TcpListener::bind(addr).await.map_err(TangoError::SockBind)?;
+
+	if let Ok(addr) = listener.local_addr() {
+		info!("Client API listening on http://{addr}/");
+	};
+
+	let routes = Router::new()
+		.route("/api/sysinfo", post(sysinfo))
+		.with_state(Arc::new(state));
+
+	axum::serve(listener, routes).await.map_err(TangoError::HttpServer)
+}
@@ -0,0 +1,62 @@
+use std::sync::Arc;
+
+use axum::{Json, extract::State};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::Deserialize;
+
+use crate::{
+    error::TangoError,
+    http::HTTPState,
+    rustdesk::{peer::PeerInfo, peer_id::PeerId},
+};
+
+#[derive(Deserialize)]
+pub struct SysInfo {
+    pub id: String,
+    #[serde(default)]
+    pub uuid: String,
+    #[serde(default)]
+    pub hostname: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub os: String,
+}
+
+// The client only looks at the response body, these are the strings it
+// understands.
+pub async fn sysinfo(
+    State(state): State<Arc<HTTPState>>,
+    Json(info): Json<SysInfo>,
+) -> Result<&'static str, TangoError> {
+    let Ok(id) = PeerId::new(&info.id) else {
+        return Ok("ID_NOT_FOUND");
+    };
+
+    let mut peers = state.peers.clone();
+
+    let Some(peer) = peers.get(id.clone()).await? else {
+        return Ok("ID_NOT_FOUND");
+    };
+
+    if STANDARD.decode(&info.uuid).ok().as_deref() != Some(peer.device_uuid.as_ref()) {
+        return Err(TangoError::Forbidden);
+    }
+
+    // Clients report e.g. "Linux / Ubuntu 24.04", discovery only wants the
+    // first part.
+    let platform = info.os.split(" / ").next().unwrap_or_default().to_owned();
+
+    peers
+        .update_info(
+            id,
+            PeerInfo {
+                hostname: info.hostname,
+                username: info.username,
+                platform,
+            },
+        )
+        .await?;
+
+    Ok("SYSINFO_UPDATED")
+}
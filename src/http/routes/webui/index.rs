@@ -0,0 +1,25 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{extract::State, response::{Html, IntoResponse}};
+
+use crate::{
+    error::TangoError,
+    http::{
+        HTTPState,
+        webui::templates::index::{IndexTemplate, SidebarItem},
+    },
+};
+
+pub async fn index(State(state): State<Arc<HTTPState>>) -> Result<impl IntoResponse, TangoError> {
+	let tmplt = IndexTemplate {
+		sidebar_items: vec![SidebarItem {
+			name: "Server",
+			image_href: "/static/assets/tango.webp",
+			image_alt: "server",
+		}],
+		public_key: state.server_key.public_key_base64(),
+	};
+
+	Ok(Html(tmplt.render()?))
+}
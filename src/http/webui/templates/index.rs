@@ -0,0 +1,14 @@
+use askama::Template;
+
+pub struct SidebarItem {
+	pub name: &'static str,
+	pub image_href: &'static str,
+	pub image_alt: &'static str,
+}
+
+#[derive(Template)]
+#[template(path = "index.html")]
+pub struct IndexTemplate {
+	pub sidebar_items: Vec<SidebarItem>,
+	pub public_key: String,
+}
@@ -1,2 +1,3 @@
+pub mod index;
 pub mod login;
 pub mod error;
@@ -1,21 +1,14 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::path::PathBuf;
 
-use conf::{TangoConfig, load_config};
+use conf::load_config;
 use db::Database;
 use error::TangoError;
-use hbb_common::{
-    Stream,
-    bytes::BytesMut,
-    protobuf::Message,
-    rendezvous_proto::{
-        RegisterPeerResponse, RegisterPkResponse, RelayResponse, RendezvousMessage, RequestRelay,
-        register_pk_response, rendezvous_message,
-    },
-    tcp::{FramedStream, new_listener},
-    udp::FramedSocket,
+use http::{HTTPState, start_api_server, start_http_server};
+use rustdesk::{
+    discovery::LanDiscovery, import::import_hbbs, key::ServerKey, peer::PeersCollection,
+    relay::RelayServer, rendezvous::RendezvousServer,
 };
-use http::{HTTPState, start_http_server};
-use tracing::{error, info};
+use tracing::info;
 use tracing_subscriber::{EnvFilter, fmt};
 
 mod auth;
@@ -25,6 +18,42 @@ mod error;
 mod http;
 mod rustdesk;
 
+enum Command {
+    Serve,
+    Migrate,
+    ImportHbbs { dir: PathBuf, force: bool },
+}
+
+fn parse_command() -> Result<Command, TangoError> {
+    let mut args = std::env::args().skip(1);
+
+    match args.next().as_deref() {
+        None => Ok(Command::Serve),
+        Some("migrate") => Ok(Command::Migrate),
+        Some("import-hbbs") => {
+            let (mut dir, mut force) = (None, false);
+            for arg in args {
+                if arg == "--force" {
+                    force = true;
+                } else if arg.starts_with("--") || dir.is_some() {
+                    return Err(TangoError::UnknownCommand(arg));
+                } else {
+                    dir = Some(arg);
+                }
+            }
+
+            let dir = dir.ok_or(TangoError::MissingArgument(
+                "import-hbbs [--force] <hbbs data directory>",
+            ))?;
+            Ok(Command::ImportHbbs {
+                dir: dir.into(),
+                force,
+            })
+        }
+        Some(cmd) => Err(TangoError::UnknownCommand(cmd.to_owned())),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), TangoError> {
     tracing_subscriber::fmt()
@@ -34,145 +63,71 @@ async fn main() -> Result<(), TangoError> {
         .with_timer(fmt::time::UtcTime::rfc_3339())
         .init();
 
+    let command = parse_command()?;
     let config = load_config()?;
     let db = Database::new(&config.database_url).await?;
 
-    let addr = config.webui.http_addr.clone();
+    db.check_schema().await?;
+    if let Command::Migrate = command {
+        db.migrate().await?;
+        info!("Database migrations applied");
+        return Ok(());
+    }
 
-    let state = HTTPState { config, db };
+    if config.auto_migrate {
+        db.migrate().await?;
+    }
 
+    if let Command::ImportHbbs { dir, force } = command {
+        return import_hbbs(&dir, &db, force).await;
+    }
+
+    let server_key = ServerKey::load_or_generate()?;
+    let reg_pk_quota = config.rustdesksrv.rate_limit.reg_pk_quota()?;
+    let peers = PeersCollection::new(db.clone(), reg_pk_quota).await;
+
+    info!("Server public key: {}", server_key.public_key_base64());
+
+    let addr = config.webui.http_addr.clone();
+    let api_addr = config.webui.api_addr.clone();
+    let rustdesk_config = config.rustdesksrv.clone();
+
+    let state = HTTPState {
+        config,
+        db: db.clone(),
+        server_key: server_key.clone(),
+        peers: peers.clone(),
+    };
+
+    let api_task = (rustdesk_config.lan_discovery && !api_addr.is_empty())
+        .then(|| tokio::spawn(start_api_server(api_addr, state.clone())));
     let http_task = tokio::spawn(start_http_server(addr, state));
+    let relay_task = tokio::spawn(RelayServer::start(rustdesk_config.clone(), server_key.clone()));
+    let discovery_task = rustdesk_config
+        .lan_discovery
+        .then(|| tokio::spawn(LanDiscovery::start(rustdesk_config.clone(), peers.clone())));
+    let rendezvous_task = tokio::spawn(RendezvousServer::start(rustdesk_config, peers, server_key));
 
 	tokio::select! {
 		res = http_task => res.map_err(TangoError::Join)??,
+		res = rendezvous_task => res.map_err(TangoError::Join)??,
+		res = relay_task => res.map_err(TangoError::Join)??,
+		res = async {
+			match api_task {
+				Some(task) => task.await,
+				None => std::future::pending().await,
+			}
+		} => res.map_err(TangoError::Join)??,
+		res = async {
+			match discovery_task {
+				Some(task) => task.await,
+				None => std::future::pending().await,
+			}
+		} => res.map_err(TangoError::Join)??,
 		_ = tokio::signal::ctrl_c() => {
 			info!("Shutdown signal received");
 		}
 	}
 
     Ok(())
-
-    // let mut sock = FramedSocket::new("0.0.0.0:21116").await.inspect_err(|e| error!(net_error=%e, "Error binding UDP socket")).unwrap();
-    // let mut listener = new_listener("0.0.0.0:21116", false).await.inspect_err(|e| error!(net_error=%e, "Error binding to TCP socket")).unwrap();
-    // let mut rlistener = new_listener("0.0.0.0:21117", false).await.inspect_err(|e| error!(net_error=%e, "Error binding to relay TCP socket")).unwrap();
-
-    // let mut id_map = HashMap::new();
-    // let relay_server = std::env::var("IP").inspect_err(|e| error!(env_err=%e, "Error reading IP environment variable")).unwrap();
-    // let mut saved_stream: Option<FramedStream> = None;
-
-    // loop {
-    //     tokio::select! {
-    //         Some(Ok((bytes, addr))) = sock.next() => {
-    //             handle_udp(&mut sock, bytes, addr.into(), &mut id_map).await;
-    //         }
-    //         Ok((stream, addr)) = listener.accept() => {
-    //             let mut stream = FramedStream::from(stream, addr);
-    //             if let Some(Ok(bytes)) = stream.next_timeout(3000).await {
-    //                 if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(&bytes) {
-    //                     match msg_in.union {
-    //                         Some(rendezvous_message::Union::PunchHoleRequest(ph)) => {
-    //                             info!{peer_id = %ph.id, "recived PunchHoleRequest"};
-    //                             if let Some(addr) = id_map.get(&ph.id) {
-    //                                 let mut msg_out = RendezvousMessage::new();
-    //                                 msg_out.set_request_relay(RequestRelay {
-    //                                     relay_server: relay_server.clone(),
-    //                                     ..Default::default()
-    //                                 });
-    //                                 sock.send(&msg_out, addr.clone()).await.ok();
-    //                                 saved_stream = Some(stream);
-    //                             }
-    //                         },
-    //                         Some(rendezvous_message::Union::RelayResponse(_)) => {
-    //                             info!{peer_addr = %addr, "recived RelayResponse"};
-    //                             let mut msg_out = RendezvousMessage::new();
-    //                             msg_out.set_relay_response(RelayResponse {
-    //                                 relay_server: relay_server.clone(),
-    //                                 ..Default::default()
-    //                             });
-
-    //                             if let Some(mut stream) = saved_stream.take() {
-    //                                 stream.send(&msg_out).await.ok();
-    //                                 if let Ok((stream_a, _)) = rlistener.accept().await {
-    //                                     let mut stream_a = FramedStream::from(stream_a, addr);
-    //                                     stream_a.next_timeout(3000).await;
-    //                                     if let Ok((stream_b, _)) = rlistener.accept().await {
-    //                                         let mut stream_b = FramedStream::from(stream_b, addr);
-    //                                         stream_b.next_timeout(3000).await;
-    //                                         relay(stream_a, stream_b, &mut sock, &mut id_map).await;
-    //                                     }
-    //                                 }
-    //                             }
-    //                         }
-    //                         _ => {}
-    //                     }
-    //                 }
-    //             }
-    //         }
-    //     }
-    // }
-}
-
-async fn relay(
-    stream: FramedStream,
-    peer: FramedStream,
-    sock: &mut FramedSocket,
-    id_map: &mut HashMap<String, SocketAddr>,
-) {
-    let mut peer = peer;
-    let mut stream = stream;
-
-    peer.set_raw();
-    stream.set_raw();
-
-    loop {
-        tokio::select! {
-            Some(Ok((bytes, addr))) = sock.next() => {
-                handle_udp(sock, bytes, addr.into(), id_map).await;
-            }
-            res = peer.next() => {
-                if let Some(Ok(bytes)) = res {
-                    stream.send_bytes(bytes.into()).await.ok();
-                } else {
-                    break;
-                }
-            }
-            res = stream.next() => {
-                if let Some(Ok(bytes)) = res {
-                    peer.send_bytes(bytes.into()).await.ok();
-                } else {
-                    break;
-                }
-            }
-        }
-    }
-}
-
-#[tracing::instrument(skip(sock, id_map), fields(peer_addr = %addr))]
-async fn handle_udp(
-    sock: &mut FramedSocket,
-    bytes: BytesMut,
-    addr: SocketAddr,
-    id_map: &mut HashMap<String, SocketAddr>,
-) {
-    if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(&bytes) {
-        match msg_in.union {
-            Some(rendezvous_message::Union::RegisterPeer(rp)) => {
-                info!(peer_id = %rp.id, peer_addr = %addr, "Registering peer");
-                id_map.insert(rp.id, addr);
-                let mut msg_out = RendezvousMessage::new();
-                msg_out.set_register_peer_response(RegisterPeerResponse::new());
-                sock.send(&msg_out, addr).await.ok();
-            }
-            Some(rendezvous_message::Union::RegisterPk(_)) => {
-                info!("Received RegisterPk message from {}", addr);
-                let mut msg_out = RendezvousMessage::new();
-                msg_out.set_register_pk_response(RegisterPkResponse {
-                    result: register_pk_response::Result::OK.into(),
-                    ..Default::default()
-                });
-                sock.send(&msg_out, addr).await.ok();
-            }
-            _ => {}
-        }
-    }
 }
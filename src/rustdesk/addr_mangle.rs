@@ -0,0 +1,139 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::peer::normalize_addr;
+
+/// Encodes `addr` the way RustDesk expects in `socket_addr` fields.
+///
+/// IPv4 addresses are salted with the current time so the same address
+/// doesn't always produce the same bytes, IPv6 addresses are sent as-is
+/// followed by the port.
+pub fn encode(addr: SocketAddr) -> Vec<u8> {
+    let tm = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u32;
+
+    encode_with_timestamp(addr, tm)
+}
+
+pub fn encode_with_timestamp(addr: SocketAddr, tm: u32) -> Vec<u8> {
+    match normalize_addr(addr) {
+        SocketAddr::V4(addr) => {
+            let tm = tm as u128;
+            let ip = u32::from_le_bytes(addr.ip().octets()) as u128;
+            let port = addr.port() as u128;
+            let v = ((ip + tm) << 49) | (tm << 17) | (port + (tm & 0xFFFF));
+
+            let bytes = v.to_le_bytes();
+            let len = bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+            bytes[..len].to_vec()
+        }
+        SocketAddr::V6(addr) => {
+            let mut bytes = addr.ip().octets().to_vec();
+            bytes.extend_from_slice(&addr.port().to_le_bytes());
+            bytes
+        }
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Option<SocketAddr> {
+    if bytes.len() > 16 {
+        let (ip, port) = bytes.split_first_chunk::<16>()?;
+        let port: [u8; 2] = port.try_into().ok()?;
+
+        let addr = SocketAddr::new(Ipv6Addr::from(*ip).into(), u16::from_le_bytes(port));
+        return Some(normalize_addr(addr));
+    }
+
+    let mut padded = [0u8; 16];
+    padded[..bytes.len()].copy_from_slice(bytes);
+    let n = u128::from_le_bytes(padded);
+
+    let tm = (n >> 17) & u32::MAX as u128;
+    let ip = ((n >> 49).wrapping_sub(tm) as u32).to_le_bytes();
+    let port = (n & 0xFFFFFF).wrapping_sub(tm & 0xFFFF) as u16;
+
+    Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddrV6};
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn known_ipv4_vectors() {
+        let addr: SocketAddr = "1.2.3.4:21116".parse().unwrap();
+
+        assert_eq!(
+            encode_with_timestamp(addr, 0),
+            [0x7c, 0x52, 0x00, 0x00, 0x00, 0x00, 0x02, 0x04, 0x06, 0x08]
+        );
+        assert_eq!(
+            encode_with_timestamp(addr, 0x12345678),
+            [0xf4, 0xa8, 0xf0, 0xac, 0x68, 0x24, 0xf2, 0xb0, 0x6e, 0x2c]
+        );
+
+        let addr: SocketAddr = "1.2.3.4:65535".parse().unwrap();
+        assert_eq!(
+            encode_with_timestamp(addr, u32::MAX),
+            [0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 0x04, 0x06, 0x08, 0x02]
+        );
+    }
+
+    #[test]
+    fn known_ipv6_vector() {
+        let addr: SocketAddr = "[2001:db8::1]:21116".parse().unwrap();
+
+        let mut expected = vec![0x20, 0x01, 0x0d, 0xb8];
+        expected.extend_from_slice(&[0; 11]);
+        expected.extend_from_slice(&[0x01, 0x7c, 0x52]);
+
+        assert_eq!(encode_with_timestamp(addr, 0x12345678), expected);
+        assert_eq!(decode(&expected), Some(addr));
+    }
+
+    #[test]
+    fn mapped_ipv4_is_encoded_as_ipv4() {
+        let mapped: SocketAddr = "[::ffff:1.2.3.4]:21116".parse().unwrap();
+        let plain: SocketAddr = "1.2.3.4:21116".parse().unwrap();
+
+        assert_eq!(encode_with_timestamp(mapped, 42), encode_with_timestamp(plain, 42));
+    }
+
+    #[test]
+    fn invalid_length_is_rejected() {
+        assert_eq!(decode(&[0; 17]), None);
+        assert_eq!(decode(&[0; 19]), None);
+    }
+
+    proptest! {
+        #[test]
+        fn ipv4_round_trip(ip: [u8; 4], port: u16, tm: u32) {
+            let addr = SocketAddr::new(IpAddr::from(ip), port);
+            prop_assert_eq!(decode(&encode_with_timestamp(addr, tm)), Some(addr));
+        }
+
+        #[test]
+        fn ipv6_round_trip(ip: [u8; 16], port: u16, tm: u32) {
+            let ip = Ipv6Addr::from(ip);
+            prop_assume!(ip.to_ipv4_mapped().is_none());
+
+            let addr = SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0));
+            prop_assert_eq!(decode(&encode_with_timestamp(addr, tm)), Some(addr));
+        }
+
+        #[test]
+        fn matches_hbb_common(ip: [u8; 4], port: u16, tm: u32) {
+            let addr = SocketAddr::new(IpAddr::from(ip), port);
+            let encoded = encode_with_timestamp(addr, tm);
+            prop_assert_eq!(hbb_common::AddrMangle::decode(&encoded), addr);
+        }
+    }
+}
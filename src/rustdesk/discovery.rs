@@ -0,0 +1,158 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
+use hbb_common::{
+    protobuf::Message,
+    rendezvous_proto::{PeerDiscovery, RendezvousMessage, rendezvous_message},
+    udp::FramedSocket,
+};
+use nonzero::nonzero;
+use tokio::time::interval;
+use tracing::{debug, info, trace};
+
+use crate::{
+    conf::RustdeskSrvConfig,
+    error::{IOError, TangoError, TangoResult},
+};
+
+use super::{
+    peer::{PeersCollection, normalize_addr},
+    rendezvous::message_kind,
+};
+
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(60);
+/// A single ping can be answered with every online peer, so each source only
+/// gets a few answered.
+const PING_QUOTA: Quota = Quota::per_second(nonzero!(2u32));
+
+/// Answers the broadcast pings RustDesk clients send to find peers on their
+/// LAN with the peers we know about on the same subnet.
+pub struct LanDiscovery {
+    peers: PeersCollection,
+    online_timeout: Duration,
+    prefix_v4: u8,
+    prefix_v6: u8,
+    rate_limiter: DefaultKeyedRateLimiter<IpAddr>,
+}
+
+impl LanDiscovery {
+    pub async fn start(config: RustdeskSrvConfig, peers: PeersCollection) -> TangoResult<()> {
+        let addr = SocketAddr::new(config.bind_addr, config.lan_discovery_port as u16);
+        let mut socket = FramedSocket::new(addr).await?;
+
+        info!("LAN discovery listening on {}", addr);
+
+        let srv = Self {
+            peers,
+            online_timeout: Duration::from_secs(config.online_timeout_secs),
+            prefix_v4: config.lan_discovery_prefix_v4,
+            prefix_v6: config.lan_discovery_prefix_v6,
+            rate_limiter: RateLimiter::keyed(PING_QUOTA),
+        };
+
+        let mut housekeeping = interval(HOUSEKEEPING_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = housekeeping.tick() => {
+                    srv.rate_limiter.retain_recent();
+                }
+                res = socket.next() => match res {
+                    Some(Ok((bytes, addr))) => {
+                        if let Err(err) = srv.handle_ping(&bytes, addr.into(), &mut socket).await {
+                            debug!("Error answering discovery ping: {}", err);
+                        }
+                    }
+                    Some(Err(err)) => {
+                        tracing::error!("LAN discovery socket error: {}", err);
+                    }
+                    None => return Err(TangoError::IOError(IOError::DiscoverySocket)),
+                },
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self, bytes, socket), fields(peer_addr = %addr))]
+    async fn handle_ping(
+        &self,
+        bytes: &[u8],
+        addr: SocketAddr,
+        socket: &mut FramedSocket,
+    ) -> TangoResult<()> {
+        // Addresses seen by an internet facing server are public ones, peers
+        // behind the same ISP block aren't on the client's LAN.
+        let source = normalize_addr(addr).ip();
+        if !is_lan_address(source) {
+            trace!("Ignoring discovery ping from a public address");
+            return Ok(());
+        }
+
+        if self.rate_limiter.check_key(&source).is_err() {
+            trace!("Ignoring discovery ping, source is over the rate limit");
+            return Ok(());
+        }
+
+        let msg = RendezvousMessage::parse_from_bytes(bytes)
+            .map_err(|_| TangoError::RendezvousError { kind: "Malformed", addr })?;
+
+        let kind = message_kind(&msg);
+        let Some(rendezvous_message::Union::PeerDiscovery(discovery)) = msg.union else {
+            return Err(TangoError::RendezvousError { kind, addr });
+        };
+
+        if discovery.cmd != "ping" {
+            return Ok(());
+        }
+
+        for peer in self.peers.all_in_memory().await {
+            if !peer.is_online(self.online_timeout)
+                || !self.same_subnet(peer.socket_address.ip(), source)
+            {
+                continue;
+            }
+
+            trace!("Announcing {} to {}", peer.peer_id, addr);
+
+            let mut msg = RendezvousMessage::new();
+            msg.set_peer_discovery(PeerDiscovery {
+                cmd: "pong".to_owned(),
+                id: peer.peer_id.to_string(),
+                hostname: peer.info.hostname,
+                username: peer.info.username,
+                platform: peer.info.platform,
+                ..Default::default()
+            });
+            socket.send(&msg, addr).await?;
+        }
+
+        Ok(())
+    }
+
+    fn same_subnet(&self, a: IpAddr, b: IpAddr) -> bool {
+        match (a, b) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => {
+                let mask = u32::MAX
+                    .checked_shl(32u32.saturating_sub(self.prefix_v4.into()))
+                    .unwrap_or(0);
+                u32::from(a) & mask == u32::from(b) & mask
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) => {
+                let mask = u128::MAX
+                    .checked_shl(128u32.saturating_sub(self.prefix_v6.into()))
+                    .unwrap_or(0);
+                u128::from(a) & mask == u128::from(b) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn is_lan_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_unique_local() || ip.is_unicast_link_local(),
+    }
+}
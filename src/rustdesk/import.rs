@@ -0,0 +1,111 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+};
+
+use serde::Deserialize;
+use sqlx::{SqlitePool, sqlite::SqliteConnectOptions};
+use tracing::{info, warn};
+
+use crate::{
+    db::Database,
+    error::{PeerError, TangoError, TangoResult},
+};
+
+use super::{
+    key::{ServerKey, key_dir},
+    peer::Peer,
+    peer_id::PeerId,
+};
+
+const HBBS_DATABASE_FILE: &str = "db_v2.sqlite3";
+
+#[derive(sqlx::FromRow)]
+struct HbbsPeer {
+    id: String,
+    uuid: Vec<u8>,
+    pk: Vec<u8>,
+    info: String,
+}
+
+/// hbbs keeps the last registration IP in the peer's `info` column.
+#[derive(Deserialize, Default)]
+struct HbbsInfo {
+    #[serde(default)]
+    ip: String,
+}
+
+/// Takes over the keypair and registered peers of an hbbs installation, so
+/// clients configured for it keep working against tango. An existing,
+/// different server key is only replaced with `force`.
+pub async fn import_hbbs(dir: &Path, db: &Database, force: bool) -> TangoResult<()> {
+    let key = ServerKey::load(dir)?
+        .ok_or(TangoError::ServerKey("No id_ed25519 in the hbbs directory"))?;
+    let key_dir = key_dir()?;
+
+    let replace_key = match ServerKey::load(&key_dir)? {
+        Some(existing) if existing.pk == key.pk => false,
+        Some(_) if !force => {
+            return Err(TangoError::ServerKey(
+                "A different server key exists, pass --force to replace it",
+            ));
+        }
+        _ => true,
+    };
+
+    let options = SqliteConnectOptions::new()
+        .filename(dir.join(HBBS_DATABASE_FILE))
+        .read_only(true);
+    let pool = SqlitePool::connect_with(options).await?;
+
+    let rows = sqlx::query_as::<_, HbbsPeer>("SELECT id, uuid, pk, info FROM peer")
+        .fetch_all(&pool)
+        .await?;
+    pool.close().await;
+
+    let (mut imported, mut skipped) = (0, 0);
+
+    for row in rows {
+        let Ok(peer_id) = PeerId::new(&row.id) else {
+            warn!("Skipping peer {}, invalid ID", row.id);
+            skipped += 1;
+            continue;
+        };
+
+        let info: HbbsInfo = serde_json::from_str(&row.info).unwrap_or_default();
+        let ip = info.ip.parse().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+        // Only the IP is known until the peer registers again, port 0 keeps
+        // that first registration from being treated as an IP change.
+        let peer = Peer {
+            socket_address: SocketAddr::new(ip, 0),
+            peer_id,
+            device_uuid: row.uuid.into(),
+            pk: row.pk.into(),
+            ..Default::default()
+        };
+
+        match db.create_peer(peer).await {
+            Ok(()) => imported += 1,
+            Err(TangoError::PeerError(PeerError::AlreadyExists)) => {
+                warn!("Skipping peer {}, already known", row.id);
+                skipped += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    info!("Imported {} peers from hbbs, skipped {}", imported, skipped);
+
+    if replace_key {
+        key.store(&key_dir)?;
+
+        info!(
+            "Imported server key {} into {}",
+            key.public_key_base64(),
+            key_dir.display()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use hbb_common::sodiumoxide::{self, crypto::sign};
+use tracing::info;
+
+use crate::error::{TangoError, TangoResult};
+
+const SECRET_KEY_FILE: &str = "id_ed25519";
+const PUBLIC_KEY_FILE: &str = "id_ed25519.pub";
+
+#[derive(Clone)]
+pub struct ServerKey {
+    pub pk: sign::PublicKey,
+    sk: sign::SecretKey,
+}
+
+impl ServerKey {
+    pub fn load_or_generate() -> TangoResult<Self> {
+        sodiumoxide::init().map_err(|_| TangoError::ServerKey("Error initialising libsodium"))?;
+
+        let dir = key_dir()?;
+
+        if let Some(key) = Self::load(&dir)? {
+            return Ok(key);
+        }
+
+        let (pk, sk) = sign::gen_keypair();
+        let key = Self { pk, sk };
+        key.store(&dir)?;
+
+        info!("Generated new server key in {}", dir.display());
+
+        Ok(key)
+    }
+
+    pub fn from_secret_key(sk: &[u8]) -> TangoResult<Self> {
+        let sk = sign::SecretKey::from_slice(sk)
+            .ok_or(TangoError::ServerKey("Secret key has the wrong length"))?;
+
+        Ok(Self { pk: sk.public_key(), sk })
+    }
+
+    pub fn load(dir: &Path) -> TangoResult<Option<Self>> {
+        let encoded = match fs::read_to_string(dir.join(SECRET_KEY_FILE)) {
+            Ok(encoded) => encoded,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(TangoError::KeyFile(e)),
+        };
+
+        let sk = STANDARD
+            .decode(encoded.trim())
+            .map_err(|_| TangoError::ServerKey("Secret key is not valid base64"))?;
+
+        Self::from_secret_key(&sk).map(Some)
+    }
+
+    pub fn store(&self, dir: &Path) -> TangoResult<()> {
+        fs::create_dir_all(dir).map_err(TangoError::KeyFile)?;
+        write_secret(&dir.join(SECRET_KEY_FILE), STANDARD.encode(self.sk.as_ref()))
+            .map_err(TangoError::KeyFile)?;
+        fs::write(dir.join(PUBLIC_KEY_FILE), self.public_key_base64())
+            .map_err(TangoError::KeyFile)?;
+
+        Ok(())
+    }
+
+    /// The key clients have to be configured with, as shown in the RustDesk
+    /// network settings.
+    pub fn public_key_base64(&self) -> String {
+        STANDARD.encode(self.pk.as_ref())
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        sign::sign(data, &self.sk)
+    }
+}
+
+/// Only the owner may read the secret key, an existing file is tightened
+/// before it's overwritten.
+fn write_secret(path: &Path, contents: String) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        options.mode(0o600);
+        if path.exists() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+/// The keypair lives next to the confy config file.
+pub fn key_dir() -> TangoResult<PathBuf> {
+    let config_path = confy::get_configuration_file_path("tango", None)?;
+
+    Ok(config_path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default())
+}
@@ -1,3 +1,8 @@
+pub mod addr_mangle;
+pub mod discovery;
+pub mod import;
+pub mod key;
 pub mod rendezvous;
 pub mod peer;
 pub mod peer_id;
+pub mod relay;
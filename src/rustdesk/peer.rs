@@ -1,6 +1,7 @@
 use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
 use hbb_common::bytes::Bytes;
 use nonzero::nonzero;
+use serde::{Deserialize, Serialize};
 use sqlx::database;
 use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{sync::RwLock, time::Instant};
@@ -12,6 +13,8 @@ use crate::{
 
 use super::peer_id::PeerId;
 
+const LAST_SEEN_SAVE_INTERVAL: Duration = Duration::from_secs(300);
+
 #[derive(Clone)]
 pub struct Peer {
     pub socket_address: SocketAddr,
@@ -19,6 +22,37 @@ pub struct Peer {
     pub device_uuid: Bytes,
     pub reg_pk_rate_limiter: Arc<DefaultDirectRateLimiter>,
     pub pk: Bytes,
+    pub last_reg_time: Option<Instant>,
+    /// When the last heartbeat was written to the database.
+    pub last_seen_saved: Option<Instant>,
+    pub serial: i32,
+    pub info: PeerInfo,
+}
+
+/// Details reported by the client's sysinfo upload.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub hostname: String,
+    pub username: String,
+    pub platform: String,
+}
+
+/// IPv4 clients reaching a dual-stack socket show up as `::ffff:a.b.c.d`,
+/// fold those back so the same client always has the same address.
+pub fn normalize_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+impl Peer {
+    pub fn is_online(&self, timeout: Duration) -> bool {
+        self.last_reg_time.is_some_and(|t| t.elapsed() < timeout)
+    }
 }
 
 impl Default for Peer {
@@ -33,26 +67,35 @@ impl Default for Peer {
                     .allow_burst(nonzero!(3u32)),
             )),
             pk: Bytes::new(),
+            last_reg_time: None,
+            last_seen_saved: None,
+            serial: 0,
+            info: PeerInfo::default(),
         }
     }
 }
 
+#[derive(Clone)]
 pub struct PeersCollection {
     peers: Arc<RwLock<HashMap<PeerId, Peer>>>, // TODO Implement proper caching
+    reg_pk_quota: Quota,
     pub db: Database,
 }
 
 impl PeersCollection {
-    pub async fn new(db: Database) -> Self {
+    pub async fn new(db: Database, reg_pk_quota: Quota) -> Self {
         Self {
             peers: Default::default(),
+            reg_pk_quota,
             db,
         }
     }
 
-    pub async fn add(&mut self, peer: Peer) -> TangoResult<Peer> {
+    pub async fn add(&mut self, mut peer: Peer) -> TangoResult<Peer> {
         let mut peer_map = self.peers.write().await;
 
+        peer.reg_pk_rate_limiter = Arc::new(RateLimiter::direct(self.reg_pk_quota));
+
         match peer_map.entry(peer.peer_id.clone()) {
             std::collections::hash_map::Entry::Occupied(_) => Err(TangoError::PeerError(
                 crate::error::PeerError::AlreadyExists,
@@ -64,6 +107,44 @@ impl PeersCollection {
         }
     }
 
+    pub async fn rename(
+        &mut self,
+        old_id: PeerId,
+        id: PeerId,
+        addr: SocketAddr,
+        pk: Bytes,
+    ) -> TangoResult<()> {
+        let mut peer_map = self.peers.write().await;
+
+        if peer_map.contains_key(&id) {
+            return Err(TangoError::PeerError(
+                crate::error::PeerError::AlreadyExists,
+            ));
+        }
+
+        let Some(mut peer) = peer_map.remove(&old_id) else {
+            return Err(TangoError::PeerError(crate::error::PeerError::DoesntExist));
+        };
+
+        if let Err(err) = self
+            .db
+            .rename_peer(old_id.clone(), id.clone(), addr, pk.clone())
+            .await
+        {
+            peer_map.insert(old_id, peer);
+            return Err(err);
+        }
+
+        peer.peer_id = id.clone();
+        peer.socket_address = addr;
+        peer.pk = pk;
+        peer.last_reg_time = Some(Instant::now());
+        peer.last_seen_saved = peer.last_reg_time;
+        peer_map.insert(id, peer);
+
+        Ok(())
+    }
+
     pub async fn delete_id(&mut self, id: PeerId) -> TangoResult<()> {
         let mut peer_map = self.peers.write().await;
 
@@ -78,6 +159,13 @@ impl PeersCollection {
         }
     }
 
+    pub async fn delete_uuid(&mut self, uuid: Bytes) -> TangoResult<()> {
+        let mut peer_map = self.peers.write().await;
+
+        peer_map.retain(|_, p| p.device_uuid != uuid);
+        self.db.remove_peer_by_uuid(uuid).await
+    }
+
     pub async fn get(&mut self, id: PeerId) -> TangoResult<Option<Peer>> {
         let mut peer_map = self.peers.write().await;
 
@@ -87,7 +175,8 @@ impl PeersCollection {
             }
             std::collections::hash_map::Entry::Vacant(_) => {
                 match self.db.select_peer_by_id(id.clone()).await? {
-                    Some(p) => {
+                    Some(mut p) => {
+                        p.reg_pk_rate_limiter = Arc::new(RateLimiter::direct(self.reg_pk_quota));
                         peer_map.insert(id, p.clone());
                         Ok(Some(p))
                     }
@@ -97,6 +186,16 @@ impl PeersCollection {
         }
     }
 
+    /// Only looks at peers that registered with this process, which are the
+    /// only ones that can be online.
+    pub async fn get_in_memory(&self, id: &PeerId) -> Option<Peer> {
+        self.peers.read().await.get(id).cloned()
+    }
+
+    pub async fn all_in_memory(&self) -> Vec<Peer> {
+        self.peers.read().await.values().cloned().collect()
+    }
+
     pub async fn update(&mut self, id: PeerId, peer: Peer) -> TangoResult<()> {
         let mut peer_map = self.peers.write().await;
 
@@ -111,4 +210,62 @@ impl PeersCollection {
 
         Ok(())
     }
+
+    pub async fn update_pk(&mut self, id: PeerId, addr: SocketAddr, pk: Bytes) -> TangoResult<()> {
+        let mut peer_map = self.peers.write().await;
+
+        match peer_map.get_mut(&id) {
+            Some(peer) => {
+                self.db.update_peer_pk(id, addr, pk.clone()).await?;
+                peer.socket_address = addr;
+                peer.pk = pk;
+                peer.last_reg_time = Some(Instant::now());
+                peer.last_seen_saved = peer.last_reg_time;
+                Ok(())
+            }
+            None => Err(TangoError::DoesntExist),
+        }
+    }
+
+    /// Heartbeats only reach the database when something changed or the
+    /// stored last-seen time is getting stale.
+    pub async fn update_addr(
+        &mut self,
+        id: PeerId,
+        addr: SocketAddr,
+        serial: i32,
+    ) -> TangoResult<()> {
+        let mut peer_map = self.peers.write().await;
+
+        match peer_map.get_mut(&id) {
+            Some(peer) => {
+                let stale = peer
+                    .last_seen_saved
+                    .is_none_or(|t| t.elapsed() >= LAST_SEEN_SAVE_INTERVAL);
+
+                if peer.socket_address != addr || peer.serial != serial || stale {
+                    self.db.update_peer_seen(id, addr, serial).await?;
+                    peer.socket_address = addr;
+                    peer.serial = serial;
+                    peer.last_seen_saved = Some(Instant::now());
+                }
+                peer.last_reg_time = Some(Instant::now());
+                Ok(())
+            }
+            None => Err(TangoError::DoesntExist),
+        }
+    }
+
+    pub async fn update_info(&mut self, id: PeerId, info: PeerInfo) -> TangoResult<()> {
+        let mut peer_map = self.peers.write().await;
+
+        match peer_map.get_mut(&id) {
+            Some(peer) => {
+                self.db.update_peer_info(id, &info).await?;
+                peer.info = info;
+                Ok(())
+            }
+            None => Err(TangoError::DoesntExist),
+        }
+    }
 }
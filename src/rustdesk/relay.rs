@@ -0,0 +1,334 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use governor::{DefaultKeyedRateLimiter, RateLimiter};
+use hbb_common::{
+    bytes::Bytes,
+    protobuf::Message,
+    rendezvous_proto::{RendezvousMessage, rendezvous_message},
+    tcp::FramedStream,
+};
+use tokio::{
+    net::TcpStream,
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore},
+    time::{Instant, interval, sleep, timeout},
+};
+use tokio_tungstenite::{WebSocketStream, accept_async, tungstenite::Message as WsMessage};
+use tracing::{debug, info, trace};
+
+use crate::{
+    conf::RustdeskSrvConfig,
+    error::{IOError, TangoError, TangoResult},
+};
+
+use super::{
+    key::ServerKey,
+    peer::normalize_addr,
+    rendezvous::{FIRST_MESSAGE_TIMEOUT, listen, message_kind},
+};
+
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(60);
+
+enum RelayStream {
+    Tcp(FramedStream),
+    Ws(WebSocketStream<TcpStream>),
+}
+
+impl RelayStream {
+    async fn next(&mut self) -> Option<Bytes> {
+        match self {
+            RelayStream::Tcp(stream) => match stream.next().await {
+                Some(Ok(bytes)) => Some(bytes.freeze()),
+                _ => None,
+            },
+            RelayStream::Ws(stream) => loop {
+                match stream.next().await {
+                    Some(Ok(WsMessage::Binary(bytes))) => return Some(bytes),
+                    Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => return None,
+                    Some(Ok(_)) => continue,
+                }
+            },
+        }
+    }
+
+    async fn send(&mut self, bytes: Bytes) -> bool {
+        match self {
+            RelayStream::Tcp(stream) => stream.send_bytes(bytes).await.is_ok(),
+            RelayStream::Ws(stream) => stream.send(WsMessage::Binary(bytes)).await.is_ok(),
+        }
+    }
+
+    fn set_raw(&mut self) {
+        // WebSocket frames already delimit messages, nothing to unwrap.
+        if let RelayStream::Tcp(stream) = self {
+            stream.set_raw();
+        }
+    }
+}
+
+/// A connection waiting for the other half of its relay.
+struct Pending {
+    /// Tells apart connections that reused the same relay uuid.
+    token: u64,
+    stream: RelayStream,
+    addr: SocketAddr,
+    _permit: OwnedSemaphorePermit,
+}
+
+#[derive(Clone)]
+pub struct RelayServer {
+    pending: Arc<Mutex<HashMap<String, Pending>>>,
+    next_token: Arc<AtomicU64>,
+    pair_timeout: Duration,
+    idle_timeout: Duration,
+    licence_key: Option<String>,
+    connection_slots: Arc<Semaphore>,
+    rate_limiter: Arc<DefaultKeyedRateLimiter<IpAddr>>,
+}
+
+impl RelayServer {
+    pub async fn start(config: RustdeskSrvConfig, key: ServerKey) -> TangoResult<()> {
+        let addr = SocketAddr::new(config.bind_addr, config.relay_port as u16);
+        let ws_addr = SocketAddr::new(config.bind_addr, (config.relay_port + 2) as u16);
+        let listener = listen(addr).await?;
+        let ws_listener = listen(ws_addr).await?;
+
+        info!("Relay server listening on {} (WebSocket: {})", addr, ws_addr);
+
+        let srv = Self {
+            pending: Default::default(),
+            next_token: Default::default(),
+            pair_timeout: Duration::from_secs(config.relay_pair_timeout_secs),
+            idle_timeout: Duration::from_secs(config.relay_idle_timeout_secs),
+            licence_key: config.key_required.then(|| key.public_key_base64()),
+            connection_slots: Arc::new(Semaphore::new(config.max_relay_connections)),
+            rate_limiter: Arc::new(RateLimiter::keyed(config.relay_connection_quota()?)),
+        };
+
+        let mut housekeeping = interval(HOUSEKEEPING_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = housekeeping.tick() => {
+                    srv.rate_limiter.retain_recent();
+                }
+                res = listener.accept() => {
+                    match res {
+                        Ok((stream, addr)) => {
+                            let addr = normalize_addr(addr);
+                            let Some(permit) = srv.admit(addr) else {
+                                continue;
+                            };
+                            stream.set_nodelay(true).ok();
+
+                            let srv = srv.clone();
+                            tokio::spawn(async move {
+                                let stream = RelayStream::Tcp(FramedStream::from(stream, addr));
+                                let res = srv.handle_connection(stream, addr, permit).await;
+                                if let Err(err) = res {
+                                    debug!("Relay connection from {} failed: {}", addr, err);
+                                }
+                            });
+                        }
+                        Err(err) => {
+                            tracing::error!("Relay listener error: {}", err);
+                            return Err(TangoError::IOError(IOError::RelayListener));
+                        }
+                    }
+                }
+                res = ws_listener.accept() => {
+                    match res {
+                        Ok((stream, addr)) => {
+                            let addr = normalize_addr(addr);
+                            let Some(permit) = srv.admit(addr) else {
+                                continue;
+                            };
+                            stream.set_nodelay(true).ok();
+
+                            let srv = srv.clone();
+                            tokio::spawn(async move {
+                                let handshake = timeout(FIRST_MESSAGE_TIMEOUT, accept_async(stream));
+                                let stream = match handshake.await {
+                                    Ok(Ok(stream)) => RelayStream::Ws(stream),
+                                    Ok(Err(err)) => {
+                                        debug!("WebSocket handshake with {} failed: {}", addr, err);
+                                        return;
+                                    }
+                                    Err(_) => {
+                                        debug!("WebSocket handshake with {} timed out", addr);
+                                        return;
+                                    }
+                                };
+                                let res = srv.handle_connection(stream, addr, permit).await;
+                                if let Err(err) = res {
+                                    debug!("Relay connection from {} failed: {}", addr, err);
+                                }
+                            });
+                        }
+                        Err(err) => {
+                            tracing::error!("Relay WebSocket listener error: {}", err);
+                            return Err(TangoError::IOError(IOError::RelayWsListener));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The permit is held until the connection is closed, paired or not.
+    fn admit(&self, addr: SocketAddr) -> Option<OwnedSemaphorePermit> {
+        if self.rate_limiter.check_key(&addr.ip()).is_err() {
+            trace!("Dropping relay connection from {}, over the rate limit", addr);
+            return None;
+        }
+
+        let permit = self.connection_slots.clone().try_acquire_owned().ok();
+        if permit.is_none() {
+            debug!("Dropping relay connection from {}, too many open connections", addr);
+        }
+        permit
+    }
+
+    #[tracing::instrument(skip(self, stream, permit), fields(peer_addr = %addr))]
+    async fn handle_connection(
+        &self,
+        mut stream: RelayStream,
+        addr: SocketAddr,
+        permit: OwnedSemaphorePermit,
+    ) -> TangoResult<()> {
+        let Ok(Some(bytes)) = timeout(FIRST_MESSAGE_TIMEOUT, stream.next()).await else {
+            return Ok(());
+        };
+
+        let msg = RendezvousMessage::parse_from_bytes(&bytes)
+            .map_err(|_| TangoError::RendezvousError { kind: "Malformed", addr })?;
+
+        let kind = message_kind(&msg);
+        let Some(rendezvous_message::Union::RequestRelay(request_relay)) = msg.union else {
+            return Err(TangoError::RendezvousError { kind, addr });
+        };
+
+        if request_relay.uuid.is_empty() {
+            return Err(TangoError::RendezvousError { kind, addr });
+        }
+
+        if let Some(licence_key) = &self.licence_key
+            && request_relay.licence_key != *licence_key
+        {
+            info!("Refusing relay request {}, key mismatch", request_relay.uuid);
+            return Ok(());
+        }
+
+        let uuid = request_relay.uuid;
+
+        let mut pending = self.pending.lock().await;
+
+        if let Some(Pending {
+            stream: peer_stream,
+            addr: peer_addr,
+            _permit: _peer_permit,
+            ..
+        }) = pending.remove(&uuid)
+        {
+            drop(pending);
+            self.pipe(uuid, (peer_stream, peer_addr), (stream, addr)).await;
+            return Ok(());
+        }
+
+        trace!("Waiting for the other half of relay {}", uuid);
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        pending.insert(
+            uuid.clone(),
+            Pending {
+                token,
+                stream,
+                addr,
+                _permit: permit,
+            },
+        );
+        drop(pending);
+
+        // If nobody claimed the stream by now the other side isn't coming.
+        sleep(self.pair_timeout).await;
+
+        // The uuid may have been claimed and reused by another connection
+        // meanwhile, only drop the entry if it's still ours.
+        if let Entry::Occupied(entry) = self.pending.lock().await.entry(uuid)
+            && entry.get().token == token
+        {
+            info!("Relay request {} timed out", entry.key());
+            entry.remove();
+        }
+
+        Ok(())
+    }
+
+    async fn pipe(
+        &self,
+        uuid: String,
+        (mut a, addr_a): (RelayStream, SocketAddr),
+        (mut b, addr_b): (RelayStream, SocketAddr),
+    ) {
+        let started_at = Instant::now();
+        let (mut bytes_sent, mut bytes_received) = (0u64, 0u64);
+
+        info!("Relaying {} between {} and {}", uuid, addr_a, addr_b);
+
+        a.set_raw();
+        b.set_raw();
+
+        let idle = sleep(self.idle_timeout);
+        tokio::pin!(idle);
+
+        loop {
+            tokio::select! {
+                _ = &mut idle => {
+                    debug!("Closing relay {}, no traffic for {:?}", uuid, self.idle_timeout);
+                    break;
+                }
+                res = a.next() => {
+                    let Some(bytes) = res else {
+                        break;
+                    };
+
+                    bytes_sent += bytes.len() as u64;
+                    if !b.send(bytes).await {
+                        break;
+                    }
+                    idle.as_mut().reset(Instant::now() + self.idle_timeout);
+                }
+                res = b.next() => {
+                    let Some(bytes) = res else {
+                        break;
+                    };
+
+                    bytes_received += bytes.len() as u64;
+                    if !a.send(bytes).await {
+                        break;
+                    }
+                    idle.as_mut().reset(Instant::now() + self.idle_timeout);
+                }
+            }
+        }
+
+        info!(
+            "Relay {} closed after {:?}. {} -> {}: {} bytes, {} -> {}: {} bytes",
+            uuid,
+            started_at.elapsed(),
+            addr_a,
+            addr_b,
+            bytes_sent,
+            addr_b,
+            addr_a,
+            bytes_received
+        );
+    }
+}
@@ -1,41 +1,114 @@
-use std::net::SocketAddr;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use governor::{DefaultDirectRateLimiter, DefaultKeyedRateLimiter, Quota, RateLimiter};
+use nonzero::nonzero;
 
 use hbb_common::{
-    protobuf::Message,
-    rendezvous_proto::{RegisterPeerResponse, RegisterPkResponse, RendezvousMessage, register_pk_response::Result::{UUID_MISMATCH, TOO_FREQUENT}},
+    bytes::Bytes,
+    message_proto::IdPk,
+    protobuf::{Message, MessageField},
+    rendezvous_proto::{
+        ConfigUpdate, FetchLocalAddr, KeyExchange, LocalAddr, OnlineResponse, PunchHole,
+        PunchHoleRequest, PunchHoleResponse, PunchHoleSent, RegisterPeerResponse, RegisterPk,
+        RegisterPkResponse, RelayResponse, RendezvousMessage, RequestRelay, TestNatResponse,
+        punch_hole_response::Failure::{self, ID_NOT_EXIST, LICENSE_MISMATCH, OFFLINE},
+        register_pk_response::{
+            self,
+            Result::{ID_EXISTS, INVALID_ID_FORMAT, NOT_SUPPORT, OK, TOO_FREQUENT, UUID_MISMATCH},
+        },
+        rendezvous_message,
+    },
+    sodiumoxide::crypto::{box_, secretbox},
+    tcp::{FramedStream, listen_any, new_listener},
     udp::FramedSocket,
 };
-use tokio::net::{TcpListener, TcpStream};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore, mpsc},
+    time::{Instant, interval, sleep, timeout},
+};
+use tokio_tungstenite::{accept_async, tungstenite::Message as WsMessage};
 
-use tracing::{info, trace, warn};
+use tracing::{debug, info, trace, warn};
 
 use crate::{
-    db::Database,
+    conf::RustdeskSrvConfig,
     error::{PeerError, TangoError, TangoResult},
-    rustdesk::{peer::Peer, peer_id::PeerId},
+    rustdesk::{
+        peer::{Peer, normalize_addr},
+        peer_id::PeerId,
+    },
 };
 
-use super::peer::PeersCollection;
+use super::{addr_mangle, key::ServerKey, peer::PeersCollection};
 
-struct RendezvousServer {
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(60);
+const REAPER_INTERVAL: Duration = Duration::from_secs(5);
+/// Time a new connection gets to finish its handshake and send its first
+/// message, shared with the relay.
+pub const FIRST_MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+const ERROR_LOG_QUOTA: Quota = Quota::per_minute(nonzero!(10u32));
+/// Events waiting for the main loop, connection tasks stop reading from
+/// their socket while it's full.
+const TCP_EVENT_QUEUE: usize = 1024;
+/// Replies waiting to be written to a connection, extra ones are dropped.
+const OUTBOX_SIZE: usize = 16;
+
+type Sink = mpsc::Sender<RendezvousMessage>;
+
+enum TcpEvent {
+    Opened(SocketAddr, Sink),
+    Message(SocketAddr, RendezvousMessage),
+    Closed(SocketAddr),
+}
+
+struct TcpConnection {
+    sink: Sink,
+    last_seen: Instant,
+}
+
+pub struct RendezvousServer {
     peers: PeersCollection,
     ports: RendezvousServerPorts,
+    config: RustdeskSrvConfig,
+    msg_rate_limiter: DefaultKeyedRateLimiter<IpAddr>,
+    /// New peer ids registered by a source IP, so one host can't claim ids in bulk.
+    new_peer_rate_limiter: DefaultKeyedRateLimiter<IpAddr>,
+    tcp_events: mpsc::Sender<TcpEvent>,
+    tcp_connections: HashMap<SocketAddr, TcpConnection>,
+    tcp_connection_slots: Arc<Semaphore>,
+    key: ServerKey,
+    error_log_limiter: DefaultDirectRateLimiter,
+    /// Unexpected or malformed messages received, by message kind.
+    protocol_errors: HashMap<&'static str, u64>,
 }
 
-struct RendezvousServerPorts {
+pub struct RendezvousServerPorts {
+    pub bind_addr: IpAddr,
     pub main_port: i32,
     pub ws_port: i32,
     pub nat_port: i32,
 }
 
 impl RendezvousServerPorts {
-    pub fn new(main_port: i32) -> Self {
+    pub fn new(bind_addr: IpAddr, main_port: i32) -> Self {
         Self {
+            bind_addr,
             main_port,
             ws_port: main_port + 2,
             nat_port: main_port - 1,
         }
     }
+
+    pub fn addr(&self, port: i32) -> SocketAddr {
+        SocketAddr::new(self.bind_addr, port as u16)
+    }
 }
 
 pub struct RendezvousServerListeners {
@@ -44,38 +117,518 @@ pub struct RendezvousServerListeners {
     pub ws_listener: TcpListener,
 }
 
+impl RendezvousServerListeners {
+    pub async fn bind(ports: &RendezvousServerPorts) -> TangoResult<Self> {
+        Ok(Self {
+            main_listener: listen(ports.addr(ports.main_port)).await?,
+            nat_listener: listen(ports.addr(ports.nat_port)).await?,
+            ws_listener: listen(ports.addr(ports.ws_port)).await?,
+        })
+    }
+}
+
 impl RendezvousServer {
-    pub async fn start(ports: RendezvousServerPorts, db: Database) -> TangoResult<()> {
-        let peers = PeersCollection::new(db).await;
+    pub async fn start(
+        config: RustdeskSrvConfig,
+        peers: PeersCollection,
+        key: ServerKey,
+    ) -> TangoResult<()> {
+        let ports = RendezvousServerPorts::new(config.bind_addr, config.port);
+        let msg_rate_limiter = RateLimiter::keyed(config.rate_limit.message_quota()?);
+        let new_peer_rate_limiter = RateLimiter::keyed(config.rate_limit.new_peer_quota()?);
 
-        let srv = Self { peers, ports };
+        let socket = FramedSocket::new(ports.addr(ports.main_port)).await?;
+        let listeners = RendezvousServerListeners::bind(&ports).await?;
 
-        Ok(())
+        info!(
+            "Rendezvous server listening on {} (NAT test: {}, WebSocket: {})",
+            ports.addr(ports.main_port),
+            ports.nat_port,
+            ports.ws_port
+        );
+
+        let (tcp_events, tcp_events_rx) = mpsc::channel(TCP_EVENT_QUEUE);
+        let tcp_connection_slots = Arc::new(Semaphore::new(config.max_tcp_connections));
+
+        let mut srv = Self {
+            peers,
+            ports,
+            config,
+            msg_rate_limiter,
+            new_peer_rate_limiter,
+            tcp_events,
+            tcp_connections: HashMap::new(),
+            tcp_connection_slots,
+            key,
+            error_log_limiter: RateLimiter::direct(ERROR_LOG_QUOTA),
+            protocol_errors: HashMap::new(),
+        };
+
+        srv.main_io_loop(socket, listeners, tcp_events_rx).await
     }
 
-    async fn main_io_loop(&mut self, listeners: RendezvousServerListeners) -> TangoResult<()> {
+    async fn main_io_loop(
+        &mut self,
+        mut socket: FramedSocket,
+        listeners: RendezvousServerListeners,
+        mut tcp_events: mpsc::Receiver<TcpEvent>,
+    ) -> TangoResult<()> {
+        let mut housekeeping = interval(HOUSEKEEPING_INTERVAL);
+        let mut reaper = interval(REAPER_INTERVAL);
+
         // TODO Add relay checks
         loop {
             tokio::select!(
+                _ = housekeeping.tick() => {
+                    self.msg_rate_limiter.retain_recent();
+                    self.new_peer_rate_limiter.retain_recent();
+
+                    if !self.protocol_errors.is_empty() {
+                        info!("Unexpected rendezvous messages so far: {:?}", self.protocol_errors);
+                    }
+                },
+                _ = reaper.tick() => {
+                    self.reap_idle_connections();
+                },
+                res = socket.next() => {
+                    match res {
+                        Some(Ok((bytes, addr))) => {
+                            self.handle_udp(&bytes, normalize_addr(addr.into()), &mut socket)
+                                .await;
+                        },
+                        Some(Err(err)) => {
+                            tracing::error!("UDP socket error: {}", err);
+                        },
+                        None => {
+                            return Err(TangoError::IOError(crate::error::IOError::UdpSocket))
+                        },
+                    }
+                },
+                Some(event) = tcp_events.recv() => {
+                    self.handle_tcp_event(event, &mut socket).await;
+                },
                 res = listeners.main_listener.accept() => {
-                match res {
-                    Ok((stream, addr)) => {
-                        stream.set_nodelay(true).ok();
-                    },
-                    Err(err) => {
-                        tracing::error!("Main listener error: {}", err);
-                        return Err(TangoError::IOError(crate::error::IOError::MainListener))
+                    match res {
+                        Ok((stream, addr)) => {
+                            let addr = normalize_addr(addr);
+                            let Some(permit) = self.acquire_connection_slot(addr) else {
+                                continue;
+                            };
+                            stream.set_nodelay(true).ok();
+                            self.spawn_tcp_connection(stream, addr, permit);
+                        },
+                        Err(err) => {
+                            tracing::error!("Main listener error: {}", err);
+                            return Err(TangoError::IOError(crate::error::IOError::MainListener))
+                        },
+                    }
+                },
+                res = listeners.ws_listener.accept() => {
+                    match res {
+                        Ok((stream, addr)) => {
+                            let addr = normalize_addr(addr);
+                            let Some(permit) = self.acquire_connection_slot(addr) else {
+                                continue;
+                            };
+                            stream.set_nodelay(true).ok();
+                            self.spawn_ws_connection(stream, addr, permit);
+                        },
+                        Err(err) => {
+                            tracing::error!("WebSocket listener error: {}", err);
+                            return Err(TangoError::IOError(crate::error::IOError::WsListener))
+                        },
+                    }
+                },
+                res = listeners.nat_listener.accept() => {
+                    match res {
+                        Ok((stream, addr)) => {
+                            let addr = normalize_addr(addr);
+                            if self.msg_rate_limiter.check_key(&addr.ip()).is_err() {
+                                trace!("Dropping NAT test from {}, over the rate limit", addr);
+                                continue;
+                            }
+                            let Some(permit) = self.acquire_connection_slot(addr) else {
+                                continue;
+                            };
+                            self.spawn_nat_test(stream, addr, permit);
+                        },
+                        Err(err) => {
+                            tracing::error!("NAT listener error: {}", err);
+                            return Err(TangoError::IOError(crate::error::IOError::NatListener))
+                        },
+                    }
+                },
+            )
+        }
+    }
+
+    #[tracing::instrument(skip(self, bytes, socket), fields(peer_addr = %addr))]
+    async fn handle_udp(&mut self, bytes: &[u8], addr: SocketAddr, socket: &mut FramedSocket) {
+        let Ok(msg) = RendezvousMessage::parse_from_bytes(bytes) else {
+            self.handler_error(TangoError::RendezvousError { kind: "Malformed", addr });
+            return;
+        };
+
+        if self.over_rate_limit(&msg, addr) {
+            trace!("Dropping datagram, source is over the rate limit");
+            return;
+        }
+
+        match self.rendezvous_handler(msg, addr, socket).await {
+            Ok(Some(res)) => {
+                if let Err(err) = socket.send(&res, self.udp_target(addr)).await {
+                    warn!("Error replying to {}: {}", addr, err);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => self.handler_error(err),
+        }
+    }
+
+    /// RegisterPeer heartbeats aren't limited, every client behind a NAT
+    /// address sends them and dropping them would show those peers offline.
+    fn over_rate_limit(&self, msg: &RendezvousMessage, addr: SocketAddr) -> bool {
+        !matches!(msg.union, Some(rendezvous_message::Union::RegisterPeer(_)))
+            && self.msg_rate_limiter.check_key(&addr.ip()).is_err()
+    }
+
+    fn acquire_connection_slot(&self, addr: SocketAddr) -> Option<OwnedSemaphorePermit> {
+        let permit = self.tcp_connection_slots.clone().try_acquire_owned().ok();
+        if permit.is_none() {
+            debug!("Dropping connection from {}, too many open connections", addr);
+        }
+        permit
+    }
+
+    /// The permit is held until the connection task ends.
+    fn spawn_tcp_connection(
+        &self,
+        stream: TcpStream,
+        addr: SocketAddr,
+        permit: OwnedSemaphorePermit,
+    ) {
+        let events = self.tcp_events.clone();
+        let key = self.key.clone();
+        let key_required = self.config.key_required;
+
+        tokio::spawn(async move {
+            let _permit = permit;
+            let mut stream = FramedStream::from(stream, addr);
+            let (sink, mut outbox) = mpsc::channel(OUTBOX_SIZE);
+
+            // Offer the client an ephemeral box key signed with the server key,
+            // if it answers with a KeyExchange the rest of the stream is
+            // encrypted. When a key is required that answer has to come first.
+            let (our_pk_b, our_sk_b) = box_::gen_keypair();
+            let mut msg = RendezvousMessage::new();
+            msg.set_key_exchange(KeyExchange {
+                keys: vec![key.sign(our_pk_b.as_ref()).into()],
+                ..Default::default()
+            });
+
+            if stream.send(&msg).await.is_err() {
+                return;
+            }
+
+            if events.send(TcpEvent::Opened(addr, sink)).await.is_err() {
+                return;
+            }
+
+            // Clients that connect and never speak would otherwise hold a
+            // slot until the reaper notices them.
+            let first_message_deadline = sleep(FIRST_MESSAGE_TIMEOUT);
+            tokio::pin!(first_message_deadline);
+            let mut received = false;
+            let mut encrypted = false;
+
+            loop {
+                tokio::select! {
+                    _ = &mut first_message_deadline, if !received => {
+                        debug!("Closing {}, no message received", addr);
+                        break;
+                    }
+                    res = stream.next() => {
+                        let Some(Ok(bytes)) = res else {
+                            break;
+                        };
+
+                        match RendezvousMessage::parse_from_bytes(&bytes) {
+                            Ok(RendezvousMessage {
+                                union: Some(rendezvous_message::Union::KeyExchange(ex)),
+                                ..
+                            }) if !received && !encrypted => {
+                                let Some(symmetric_key) = open_key_exchange(&ex, &our_sk_b) else {
+                                    debug!("Closing {}, invalid key exchange", addr);
+                                    break;
+                                };
+
+                                stream.set_key(symmetric_key);
+                                encrypted = true;
+                            }
+                            Ok(_) if key_required && !encrypted => {
+                                debug!("Closing {}, no key exchange", addr);
+                                break;
+                            }
+                            Ok(msg) => {
+                                received = true;
+                                if events.send(TcpEvent::Message(addr, msg)).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Err(_) => {
+                                trace!("Closing {}, malformed message", addr);
+                                break;
+                            }
+                        }
+                    }
+                    msg = outbox.recv() => {
+                        let Some(msg) = msg else {
+                            break;
+                        };
+
+                        if let Err(err) = stream.send(&msg).await {
+                            debug!("Error sending to {}: {}", addr, err);
+                            break;
+                        }
+                    }
+                }
+            }
+
+            events.send(TcpEvent::Closed(addr)).await.ok();
+        });
+    }
+
+    /// Connections to the NAT port only carry a single TestNatRequest, it's
+    /// answered and the connection closed without ever being registered.
+    fn spawn_nat_test(&self, stream: TcpStream, addr: SocketAddr, permit: OwnedSemaphorePermit) {
+        let response = self.test_nat_response(addr);
+
+        tokio::spawn(async move {
+            let _permit = permit;
+            let mut stream = FramedStream::from(stream, addr);
+
+            let Ok(Some(Ok(bytes))) = timeout(FIRST_MESSAGE_TIMEOUT, stream.next()).await else {
+                return;
+            };
+
+            match RendezvousMessage::parse_from_bytes(&bytes) {
+                Ok(RendezvousMessage {
+                    union: Some(rendezvous_message::Union::TestNatRequest(_)),
+                    ..
+                }) => {
+                    if let Err(err) = stream.send(&response).await {
+                        debug!("Error sending to {}: {}", addr, err);
+                    }
+                }
+                _ => trace!("Closing {}, not a NAT test", addr),
+            }
+        });
+    }
+
+    fn spawn_ws_connection(
+        &self,
+        stream: TcpStream,
+        addr: SocketAddr,
+        permit: OwnedSemaphorePermit,
+    ) {
+        let events = self.tcp_events.clone();
+
+        tokio::spawn(async move {
+            let _permit = permit;
+            let mut stream = match timeout(FIRST_MESSAGE_TIMEOUT, accept_async(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    debug!("WebSocket handshake with {} failed: {}", addr, err);
+                    return;
+                }
+                Err(_) => {
+                    debug!("WebSocket handshake with {} timed out", addr);
+                    return;
+                }
+            };
+            let (sink, mut outbox) = mpsc::channel(OUTBOX_SIZE);
+
+            if events.send(TcpEvent::Opened(addr, sink)).await.is_err() {
+                return;
+            }
+
+            // Clients that connect and never speak would otherwise hold a
+            // slot until the reaper notices them.
+            let first_message_deadline = sleep(FIRST_MESSAGE_TIMEOUT);
+            tokio::pin!(first_message_deadline);
+            let mut received = false;
+
+            loop {
+                tokio::select! {
+                    _ = &mut first_message_deadline, if !received => {
+                        debug!("Closing {}, no message received", addr);
+                        break;
+                    }
+                    res = stream.next() => {
+                        let bytes = match res {
+                            Some(Ok(WsMessage::Binary(bytes))) => bytes,
+                            Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                            Some(Ok(_)) => continue,
+                        };
+
+                        match RendezvousMessage::parse_from_bytes(&bytes) {
+                            Ok(msg) => {
+                                received = true;
+                                if events.send(TcpEvent::Message(addr, msg)).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Err(_) => {
+                                trace!("Closing {}, malformed message", addr);
+                                break;
+                            }
+                        }
+                    }
+                    msg = outbox.recv() => {
+                        let Some(msg) = msg else {
+                            break;
+                        };
+
+                        let Ok(bytes) = msg.write_to_bytes() else {
+                            continue;
+                        };
+
+                        if let Err(err) = stream.send(WsMessage::Binary(bytes.into())).await {
+                            debug!("Error sending to {}: {}", addr, err);
+                            break;
+                        }
+                    }
+                }
+            }
+
+            events.send(TcpEvent::Closed(addr)).await.ok();
+        });
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn handle_tcp_event(&mut self, event: TcpEvent, socket: &mut FramedSocket) {
+        match event {
+            TcpEvent::Opened(addr, sink) => {
+                self.tcp_connections.insert(
+                    addr,
+                    TcpConnection {
+                        sink,
+                        last_seen: Instant::now(),
                     },
+                );
+            }
+            TcpEvent::Message(addr, msg) => {
+                if self.over_rate_limit(&msg, addr) {
+                    trace!("Dropping message from {}, source is over the rate limit", addr);
+                    return;
+                }
+
+                if let Some(conn) = self.tcp_connections.get_mut(&addr) {
+                    conn.last_seen = Instant::now();
+                }
+
+                match self.rendezvous_handler(msg, addr, socket).await {
+                    Ok(Some(res)) => {
+                        self.send_to_tcp(res, addr);
+                    }
+                    Ok(None) => {}
+                    Err(err) => self.handler_error(err),
                 }
-            })
+            }
+            TcpEvent::Closed(addr) => {
+                self.tcp_connections.remove(&addr);
+            }
+        }
+    }
+
+    /// Counts protocol errors and logs handler errors, throttled so a
+    /// misbehaving client can't flood the log.
+    fn handler_error(&mut self, err: TangoError) {
+        if let TangoError::RendezvousError { kind, .. } = &err {
+            *self.protocol_errors.entry(*kind).or_default() += 1;
+        }
+
+        if self.error_log_limiter.check().is_ok() {
+            warn!("Error handling message: {}", err);
+        } else {
+            trace!("Error handling message: {}", err);
+        }
+    }
+
+    fn send_to_tcp(&mut self, msg: RendezvousMessage, addr: SocketAddr) -> bool {
+        match self.tcp_connections.get(&addr) {
+            Some(conn) => conn.sink.try_send(msg).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Dropping the sink makes the connection task close the stream.
+    fn reap_idle_connections(&mut self) {
+        let idle_timeout = Duration::from_secs(self.config.tcp_idle_timeout_secs);
+
+        self.tcp_connections.retain(|addr, conn| {
+            let idle = conn.last_seen.elapsed() >= idle_timeout;
+            if idle {
+                debug!("Closing idle connection from {}", addr);
+            }
+            !idle
+        });
+    }
+
+    /// Prefers the TCP connection `addr` came from, falling back to UDP.
+    async fn send_to(
+        &mut self,
+        msg: RendezvousMessage,
+        addr: SocketAddr,
+        socket: &mut FramedSocket,
+    ) -> TangoResult<()> {
+        if !self.send_to_tcp(msg.clone(), addr) {
+            socket.send(&msg, self.udp_target(addr)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Addresses are kept normalised, an IPv6 socket can only reach IPv4
+    /// peers through their mapped form.
+    fn udp_target(&self, addr: SocketAddr) -> SocketAddr {
+        match (self.ports.bind_addr, addr) {
+            (IpAddr::V6(_), SocketAddr::V4(v4)) => {
+                SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+            }
+            _ => addr,
+        }
+    }
+
+    fn config_update(&self) -> ConfigUpdate {
+        ConfigUpdate {
+            serial: self.config.serial,
+            rendezvous_servers: self.config.rendezvous_servers.clone(),
+            ..Default::default()
         }
     }
 
+    /// The client compares the source ports seen on the main and NAT ports
+    /// to tell whether its NAT is symmetric.
+    fn test_nat_response(&self, addr: SocketAddr) -> RendezvousMessage {
+        let mut msg = RendezvousMessage::new();
+        msg.set_test_nat_response(TestNatResponse {
+            port: addr.port() as i32,
+            cu: MessageField::some(self.config_update()),
+            ..Default::default()
+        });
+
+        msg
+    }
+
     async fn rendezvous_handler(
         &mut self,
         msg: RendezvousMessage,
         addr: SocketAddr,
+        socket: &mut FramedSocket,
     ) -> TangoResult<Option<RendezvousMessage>> {
+        let kind = message_kind(&msg);
+
         if let Some(msg) = msg.union {
             match msg {
                 hbb_common::rendezvous_proto::rendezvous_message::Union::RegisterPeer(
@@ -83,43 +636,20 @@ impl RendezvousServer {
                 ) => {
                     if !register_peer.id.is_empty() {
                         trace!("New peer: {} {}", &register_peer.id, &addr);
-                        let ip_change = match self
-                            .peers
-                            .get(
-                                register_peer
-                                    .id
-                                    .try_into()
-                                    .map_err(|e| PeerError::IDError(e))?,
-                            )
-                            .await?
-                        {
-                            Some(p) => {
-                                let mut do_ip_change = false;
-
-                                if p.socket_address.port() != 0 {
-                                    do_ip_change = (addr.ip() != p.socket_address.ip())
-                                        && !addr.ip().is_loopback();
-                                };
+                        let id = PeerId::new(&register_peer.id).map_err(PeerError::IDError)?;
+                        let request_pk = self.update_addr(id, addr, register_peer.serial).await?;
 
-                                if do_ip_change {
-                                    Some(p.socket_address.to_string())
-                                } else {
-                                    None
-                                }
-                            }
-                            None => None,
-                        };
-
-                        if let Some(p) = ip_change {
-                            info!(
-                                "IP Change for peer {}. Old: {} New: {}",
-                                register_peer.id, addr, p
-                            );
-                        };
+                        // Clients with an outdated serial get the current
+                        // server list so they can migrate on their own.
+                        if self.config.serial > register_peer.serial {
+                            let mut msg = RendezvousMessage::new();
+                            msg.set_configure_update(self.config_update());
+                            self.send_to(msg, addr, socket).await?;
+                        }
 
                         let mut msg = RendezvousMessage::new();
                         msg.set_register_peer_response(RegisterPeerResponse {
-                            request_pk: true,
+                            request_pk,
                             ..Default::default()
                         });
 
@@ -129,181 +659,539 @@ impl RendezvousServer {
                     }
                 }
                 hbb_common::rendezvous_proto::rendezvous_message::Union::RegisterPeerResponse(
-                    register_peer_response,
-                ) => todo!(),
+                    _,
+                ) => Err(TangoError::RendezvousError { kind, addr }),
                 hbb_common::rendezvous_proto::rendezvous_message::Union::PunchHoleRequest(
                     punch_hole_request,
-                ) => todo!(),
-                hbb_common::rendezvous_proto::rendezvous_message::Union::PunchHole(punch_hole) => {
-                    todo!()
+                ) => self.punch_hole_request(punch_hole_request, addr, socket).await,
+                hbb_common::rendezvous_proto::rendezvous_message::Union::PunchHole(_) => {
+                    Err(TangoError::RendezvousError { kind, addr })
                 }
                 hbb_common::rendezvous_proto::rendezvous_message::Union::PunchHoleSent(
                     punch_hole_sent,
-                ) => todo!(),
-                hbb_common::rendezvous_proto::rendezvous_message::Union::PunchHoleResponse(
-                    punch_hole_response,
-                ) => todo!(),
-                hbb_common::rendezvous_proto::rendezvous_message::Union::FetchLocalAddr(
-                    fetch_local_addr,
-                ) => todo!(),
+                ) => {
+                    self.punch_hole_sent(punch_hole_sent, addr, socket).await?;
+                    Ok(None)
+                }
+                hbb_common::rendezvous_proto::rendezvous_message::Union::PunchHoleResponse(_) => {
+                    Err(TangoError::RendezvousError { kind, addr })
+                }
+                hbb_common::rendezvous_proto::rendezvous_message::Union::FetchLocalAddr(_) => {
+                    Err(TangoError::RendezvousError { kind, addr })
+                }
                 hbb_common::rendezvous_proto::rendezvous_message::Union::LocalAddr(local_addr) => {
-                    todo!()
+                    self.local_addr(local_addr, socket).await?;
+                    Ok(None)
+                }
+                hbb_common::rendezvous_proto::rendezvous_message::Union::ConfigureUpdate(_) => {
+                    Err(TangoError::RendezvousError { kind, addr })
                 }
-                hbb_common::rendezvous_proto::rendezvous_message::Union::ConfigureUpdate(
-                    config_update,
-                ) => todo!(),
                 hbb_common::rendezvous_proto::rendezvous_message::Union::RegisterPk(
                     register_pk,
                 ) => {
-                    if register_pk.id.is_ascii() || register_pk.pk.is_empty() {
-                        return Err(TangoError::PeerError(PeerError::RegisterPk));
-                    }
+                    let result = self.register_pk(register_pk, addr).await?;
 
-                    let id = PeerId::new(&register_pk.id).map_err(|e| PeerError::IDError(e))?;
-                    let ip = addr.ip();
-
-                    let old_peer = self.peers.get(id.clone()).await?;
-
-                    if let Some(old_peer) = old_peer {
-                        if old_peer.device_uuid != register_pk.uuid
-                            || old_peer.pk != register_pk.pk
-                            || old_peer.socket_address.ip() != ip
-                        {
-                            warn!(
-                                "Mismatch for peer: {}. Stored: {} {:?} Got: {} {:?}",
-                                id,
-                                ip,
-                                register_pk.pk,
-                                old_peer.socket_address.ip(),
-                                old_peer.pk
-                            );
+                    let mut msg = RendezvousMessage::new();
+                    msg.set_register_pk_response(RegisterPkResponse {
+                        result: result.into(),
+                        ..Default::default()
+                    });
 
-                            let mut msg = RendezvousMessage::new();
-                            msg.set_register_pk_response(RegisterPkResponse {
-                                result: UUID_MISMATCH.into(),
-                                ..Default::default()
-                            });
-
-							return Ok(Some(msg));
-                        }
-
-						// TODO: Implement rate limiting
-
-                    };
-
-                    Ok(None)
+                    Ok(Some(msg))
+                }
+                hbb_common::rendezvous_proto::rendezvous_message::Union::RegisterPkResponse(_) => {
+                    Err(TangoError::RendezvousError { kind, addr })
+                }
+                hbb_common::rendezvous_proto::rendezvous_message::Union::SoftwareUpdate(_) => {
+                    Err(TangoError::RendezvousError { kind, addr })
                 }
-                hbb_common::rendezvous_proto::rendezvous_message::Union::RegisterPkResponse(
-                    register_pk_response,
-                ) => todo!(),
-                hbb_common::rendezvous_proto::rendezvous_message::Union::SoftwareUpdate(
-                    software_update,
-                ) => todo!(),
                 hbb_common::rendezvous_proto::rendezvous_message::Union::RequestRelay(
                     request_relay,
-                ) => todo!(),
+                ) => self.request_relay(request_relay, addr, socket).await,
                 hbb_common::rendezvous_proto::rendezvous_message::Union::RelayResponse(
                     relay_response,
-                ) => todo!(),
-                hbb_common::rendezvous_proto::rendezvous_message::Union::TestNatRequest(
-                    test_nat_request,
-                ) => todo!(),
-                hbb_common::rendezvous_proto::rendezvous_message::Union::TestNatResponse(
-                    test_nat_response,
-                ) => todo!(),
-                hbb_common::rendezvous_proto::rendezvous_message::Union::PeerDiscovery(
-                    peer_discovery,
-                ) => todo!(),
+                ) => {
+                    self.relay_response(relay_response).await?;
+                    Ok(None)
+                }
+                hbb_common::rendezvous_proto::rendezvous_message::Union::TestNatRequest(_) => {
+                    Ok(Some(self.test_nat_response(addr)))
+                }
+                hbb_common::rendezvous_proto::rendezvous_message::Union::TestNatResponse(_) => {
+                    Err(TangoError::RendezvousError { kind, addr })
+                }
+                // Answered by the LAN discovery responder on its own port.
+                hbb_common::rendezvous_proto::rendezvous_message::Union::PeerDiscovery(_) => {
+                    Err(TangoError::RendezvousError { kind, addr })
+                }
                 hbb_common::rendezvous_proto::rendezvous_message::Union::OnlineRequest(
                     online_request,
-                ) => todo!(),
-                hbb_common::rendezvous_proto::rendezvous_message::Union::OnlineResponse(
-                    online_response,
-                ) => todo!(),
-                hbb_common::rendezvous_proto::rendezvous_message::Union::KeyExchange(
-                    key_exchange,
-                ) => todo!(),
+                ) => {
+                    let states = self.online_states(&online_request.peers).await;
+
+                    let mut msg = RendezvousMessage::new();
+                    msg.set_online_response(OnlineResponse {
+                        states: states.into(),
+                        ..Default::default()
+                    });
+
+                    Ok(Some(msg))
+                }
+                hbb_common::rendezvous_proto::rendezvous_message::Union::OnlineResponse(_) => {
+                    Err(TangoError::RendezvousError { kind, addr })
+                }
+                // Only valid as part of the TCP handshake, which is handled by
+                // the connection task before messages reach us.
+                hbb_common::rendezvous_proto::rendezvous_message::Union::KeyExchange(_) => {
+                    Err(TangoError::RendezvousError { kind, addr })
+                }
                 hbb_common::rendezvous_proto::rendezvous_message::Union::Hc(health_check) => {
-                    todo!()
+                    let mut msg = RendezvousMessage::new();
+                    msg.set_hc(health_check);
+
+                    Ok(Some(msg))
                 }
-                _ => return Err(TangoError::RendezvousError),
+                _ => Err(TangoError::RendezvousError { kind, addr }),
             }
         } else {
-            return Err(TangoError::RendezvousError);
+            Err(TangoError::RendezvousError { kind, addr })
         }
     }
 
-    async fn update_addr(
+    async fn punch_hole_request(
+        &mut self,
+        punch_hole_request: PunchHoleRequest,
+        addr: SocketAddr,
+        socket: &mut FramedSocket,
+    ) -> TangoResult<Option<RendezvousMessage>> {
+        if !self.licence_key_matches(&punch_hole_request.licence_key) {
+            return Ok(Some(punch_hole_failure(LICENSE_MISMATCH)));
+        }
+
+        let peer = match PeerId::new(&punch_hole_request.id) {
+            Ok(id) => self.peers.get(id).await?,
+            Err(_) => None,
+        };
+
+        let Some(peer) = peer else {
+            return Ok(Some(punch_hole_failure(ID_NOT_EXIST)));
+        };
+
+        if !peer.is_online(self.online_timeout()) {
+            return Ok(Some(punch_hole_failure(OFFLINE)));
+        }
+
+        trace!("Punch hole request from {} to {}", addr, peer.peer_id);
+
+        // The requester keeps its TCP connection open, the answer arrives
+        // once the target acknowledges with PunchHoleSent, or LocalAddr when
+        // both sit behind the same public IP and can talk over the LAN.
+        let mut msg = RendezvousMessage::new();
+        if peer.socket_address.ip() == addr.ip() {
+            msg.set_fetch_local_addr(FetchLocalAddr {
+                socket_addr: addr_mangle::encode(addr).into(),
+                relay_server: self.config.relay_host.clone(),
+                ..Default::default()
+            });
+        } else {
+            msg.set_punch_hole(PunchHole {
+                socket_addr: addr_mangle::encode(addr).into(),
+                nat_type: punch_hole_request.nat_type,
+                relay_server: self.config.relay_host.clone(),
+                ..Default::default()
+            });
+        }
+        socket.send(&msg, self.udp_target(peer.socket_address)).await?;
+
+        Ok(None)
+    }
+
+    async fn local_addr(
+        &mut self,
+        local_addr: LocalAddr,
+        socket: &mut FramedSocket,
+    ) -> TangoResult<()> {
+        let requester = addr_mangle::decode(&local_addr.socket_addr)
+            .ok_or(TangoError::InvalidSocketAddr)?;
+
+        trace!("Local address of {} is {:?}", local_addr.id, local_addr.local_addr);
+
+        let mut response = PunchHoleResponse {
+            socket_addr: local_addr.local_addr,
+            pk: self.get_pk(&local_addr.version, &local_addr.id).await?,
+            relay_server: local_addr.relay_server,
+            ..Default::default()
+        };
+        response.set_is_local(true);
+
+        let mut msg = RendezvousMessage::new();
+        msg.set_punch_hole_response(response);
+
+        self.send_to(msg, requester, socket).await
+    }
+
+    async fn punch_hole_sent(
         &mut self,
-        id: String,
+        punch_hole_sent: PunchHoleSent,
         addr: SocketAddr,
         socket: &mut FramedSocket,
     ) -> TangoResult<()> {
-        let mut ip_change = true;
+        let requester = addr_mangle::decode(&punch_hole_sent.socket_addr)
+            .ok_or(TangoError::InvalidSocketAddr)?;
+
+        trace!("Punch hole sent from {} to {}", addr, requester);
+
+        let mut response = PunchHoleResponse {
+            socket_addr: addr_mangle::encode(addr).into(),
+            pk: self.get_pk(&punch_hole_sent.version, &punch_hole_sent.id).await?,
+            relay_server: punch_hole_sent.relay_server,
+            ..Default::default()
+        };
+
+        if let Ok(nat_type) = punch_hole_sent.nat_type.enum_value() {
+            response.set_nat_type(nat_type);
+        }
+
+        let mut msg = RendezvousMessage::new();
+        msg.set_punch_hole_response(response);
+
+        self.send_to(msg, requester, socket).await
+    }
+
+    async fn request_relay(
+        &mut self,
+        mut request_relay: RequestRelay,
+        addr: SocketAddr,
+        socket: &mut FramedSocket,
+    ) -> TangoResult<Option<RendezvousMessage>> {
+        if !self.licence_key_matches(&request_relay.licence_key) {
+            return Ok(Some(relay_refused("Key mismatch")));
+        }
 
-        if let Some(old_peer) = self.peers.get(id).await? {
-            ip_change = (addr.ip() != old_peer.socket_address.ip()) && !addr.ip().is_loopback()
+        let peer = match PeerId::new(&request_relay.id) {
+            Ok(id) => self.peers.get(id).await?,
+            Err(_) => None,
         };
 
+        let Some(peer) = peer else {
+            return Ok(Some(relay_refused("ID does not exist")));
+        };
+
+        if !peer.is_online(self.online_timeout()) {
+            return Ok(Some(relay_refused("Remote desktop is offline")));
+        }
+
+        trace!("Relay request from {} to {}", addr, peer.peer_id);
+
+        request_relay.socket_addr = addr_mangle::encode(addr).into();
+        if request_relay.relay_server.is_empty() {
+            request_relay.relay_server = self.config.relay_host.clone();
+        }
+
+        let mut msg = RendezvousMessage::new();
+        msg.set_request_relay(request_relay);
+        socket.send(&msg, self.udp_target(peer.socket_address)).await?;
+
+        Ok(None)
+    }
+
+    async fn relay_response(&mut self, mut relay_response: RelayResponse) -> TangoResult<()> {
+        let requester = addr_mangle::decode(&relay_response.socket_addr)
+            .ok_or(TangoError::InvalidSocketAddr)?;
+        relay_response.socket_addr = Bytes::new();
+
+        let id = relay_response.id().to_owned();
+        if !id.is_empty() {
+            let pk = self.get_pk(&relay_response.version, &id).await?;
+            relay_response.set_pk(pk);
+        }
+
+        if relay_response.relay_server.is_empty() {
+            relay_response.relay_server = self.config.relay_host.clone();
+        }
+
+        let mut msg = RendezvousMessage::new();
+        msg.set_relay_response(relay_response);
+
+        if !self.send_to_tcp(msg, requester) {
+            debug!("Dropping relay response, {} is no longer connected", requester);
+        }
+
         Ok(())
     }
+
+    fn online_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.online_timeout_secs)
+    }
+
+    /// Packs the online state of each id into a bitmap, most significant bit
+    /// first, as expected by the client address book.
+    async fn online_states(&self, ids: &[String]) -> Vec<u8> {
+        let timeout = self.online_timeout();
+        let mut states = vec![0u8; ids.len().div_ceil(8)];
+
+        for (i, id) in ids.iter().enumerate() {
+            let Ok(id) = PeerId::new(id) else {
+                continue;
+            };
+
+            let online = self
+                .peers
+                .get_in_memory(&id)
+                .await
+                .is_some_and(|p| p.is_online(timeout));
+
+            if online {
+                states[i / 8] |= 0x80 >> (i % 8);
+            }
+        }
+
+        states
+    }
+
+    fn licence_key_matches(&self, licence_key: &str) -> bool {
+        !self.config.key_required || licence_key == self.key.public_key_base64()
+    }
+
+    async fn get_pk(&mut self, version: &str, id: &str) -> TangoResult<Bytes> {
+        // Clients too old to report a version can't handle the key.
+        if version.is_empty() {
+            return Ok(Bytes::new());
+        }
+
+        let Ok(id) = PeerId::new(id) else {
+            return Ok(Bytes::new());
+        };
+
+        let Some(peer) = self.peers.get(id).await? else {
+            return Ok(Bytes::new());
+        };
+
+        if peer.pk.is_empty() {
+            return Ok(Bytes::new());
+        }
+
+        // Signing id and key together lets the client check both against
+        // the server key it was configured with.
+        let id_pk = IdPk {
+            id: peer.peer_id.to_string(),
+            pk: peer.pk,
+            ..Default::default()
+        };
+        let id_pk = id_pk.write_to_bytes()?;
+
+        Ok(self.key.sign(&id_pk).into())
+    }
+
+    async fn register_pk(
+        &mut self,
+        register_pk: RegisterPk,
+        addr: SocketAddr,
+    ) -> TangoResult<register_pk_response::Result> {
+        if register_pk.uuid.is_empty() || register_pk.pk.is_empty() {
+            return Ok(NOT_SUPPORT);
+        }
+
+        let Ok(id) = PeerId::new(&register_pk.id) else {
+            return Ok(INVALID_ID_FORMAT);
+        };
+
+        if !register_pk.old_id.is_empty() && register_pk.old_id != register_pk.id {
+            let Ok(old_id) = PeerId::new(&register_pk.old_id) else {
+                return Ok(INVALID_ID_FORMAT);
+            };
+
+            return self.rename_peer(old_id, id, register_pk, addr).await;
+        }
+
+        let Some(old_peer) = self.peers.get(id.clone()).await? else {
+            if self.new_peer_rate_limiter.check_key(&addr.ip()).is_err() {
+                debug!("{} is registering new peers too often", addr.ip());
+                return Ok(TOO_FREQUENT);
+            }
+
+            // A device that shows up under a new id without telling us the
+            // old one replaces its previous registration.
+            self.peers.delete_uuid(register_pk.uuid.clone()).await?;
+
+            info!("New peer {} registered from {}", id, addr);
+            self.peers
+                .add(Peer {
+                    socket_address: addr,
+                    peer_id: id,
+                    device_uuid: register_pk.uuid,
+                    pk: register_pk.pk,
+                    last_reg_time: Some(Instant::now()),
+                    ..Default::default()
+                })
+                .await?;
+
+            return Ok(OK);
+        };
+
+        let ip_change = old_peer.socket_address.ip() != addr.ip();
+
+        if old_peer.device_uuid != register_pk.uuid
+            || (ip_change && old_peer.pk != register_pk.pk)
+        {
+            warn!(
+                "Mismatch for peer: {}. Stored: {} {:?} Got: {} {:?}",
+                id,
+                old_peer.socket_address.ip(),
+                old_peer.pk,
+                addr.ip(),
+                register_pk.pk
+            );
+
+            return Ok(UUID_MISMATCH);
+        }
+
+        if old_peer.reg_pk_rate_limiter.check().is_err() {
+            debug!("Peer {} is sending RegisterPk too often", id);
+            return Ok(TOO_FREQUENT);
+        }
+
+        if old_peer.pk != register_pk.pk || old_peer.socket_address != addr {
+            self.peers.update_pk(id, addr, register_pk.pk).await?;
+        }
+
+        Ok(OK)
+    }
+
+    async fn rename_peer(
+        &mut self,
+        old_id: PeerId,
+        id: PeerId,
+        register_pk: RegisterPk,
+        addr: SocketAddr,
+    ) -> TangoResult<register_pk_response::Result> {
+        let Some(old_peer) = self.peers.get(old_id.clone()).await? else {
+            return Ok(NOT_SUPPORT);
+        };
+
+        if old_peer.device_uuid != register_pk.uuid {
+            warn!("Peer {} tried to rename {} without owning it", addr, old_id);
+            return Ok(UUID_MISMATCH);
+        }
+
+        if self.peers.get(id.clone()).await?.is_some() {
+            return Ok(ID_EXISTS);
+        }
+
+        if self.new_peer_rate_limiter.check_key(&addr.ip()).is_err() {
+            debug!("{} is renaming peers too often", addr.ip());
+            return Ok(TOO_FREQUENT);
+        }
+
+        info!("Renaming peer {} to {}", old_id, id);
+        match self.peers.rename(old_id, id, addr, register_pk.pk).await {
+            Ok(()) => Ok(OK),
+            Err(TangoError::PeerError(PeerError::AlreadyExists)) => Ok(ID_EXISTS),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn update_addr(
+        &mut self,
+        id: PeerId,
+        addr: SocketAddr,
+        serial: i32,
+    ) -> TangoResult<bool> {
+        let Some(old_peer) = self.peers.get(id.clone()).await? else {
+            return Ok(true);
+        };
+
+        let ip_change = old_peer.socket_address.port() != 0
+            && addr.ip() != old_peer.socket_address.ip()
+            && !addr.ip().is_loopback();
+
+        if ip_change {
+            info!(
+                "IP Change for peer {}. Old: {} New: {}",
+                id, old_peer.socket_address, addr
+            );
+        }
+
+        // Peers that changed IP or never sent their key have to go through
+        // RegisterPk before we trust the new address.
+        let request_pk = old_peer.pk.is_empty() || ip_change;
+
+        if !request_pk {
+            self.peers.update_addr(id, addr, serial).await?;
+        }
+
+        Ok(request_pk)
+    }
+}
+
+fn punch_hole_failure(failure: Failure) -> RendezvousMessage {
+    let mut msg = RendezvousMessage::new();
+    msg.set_punch_hole_response(PunchHoleResponse {
+        failure: failure.into(),
+        ..Default::default()
+    });
+    msg
+}
+
+fn relay_refused(reason: &str) -> RendezvousMessage {
+    let mut msg = RendezvousMessage::new();
+    msg.set_relay_response(RelayResponse {
+        refuse_reason: reason.to_owned(),
+        ..Default::default()
+    });
+    msg
 }
 
-// let mut sock = FramedSocket::new("0.0.0.0:21116").await.inspect_err(|e| error!(net_error=%e, "Error binding UDP socket")).unwrap();
-// let mut listener = new_listener("0.0.0.0:21116", false).await.inspect_err(|e| error!(net_error=%e, "Error binding to TCP socket")).unwrap();
-// let mut rlistener = new_listener("0.0.0.0:21117", false).await.inspect_err(|e| error!(net_error=%e, "Error binding to relay TCP socket")).unwrap();
-
-// let mut id_map = HashMap::new();
-// let relay_server = std::env::var("IP").inspect_err(|e| error!(env_err=%e, "Error reading IP environment variable")).unwrap();
-// let mut saved_stream: Option<FramedStream> = None;
-
-// loop {
-//     tokio::select! {
-//         Some(Ok((bytes, addr))) = sock.next() => {
-//             handle_udp(&mut sock, bytes, addr.into(), &mut id_map).await;
-//         }
-//         Ok((stream, addr)) = listener.accept() => {
-//             let mut stream = FramedStream::from(stream, addr);
-//             if let Some(Ok(bytes)) = stream.next_timeout(3000).await {
-//                 if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(&bytes) {
-//                     match msg_in.union {
-//                         Some(rendezvous_message::Union::PunchHoleRequest(ph)) => {
-//                             info!{peer_id = %ph.id, "recived PunchHoleRequest"};
-//                             if let Some(addr) = id_map.get(&ph.id) {
-//                                 let mut msg_out = RendezvousMessage::new();
-//                                 msg_out.set_request_relay(RequestRelay {
-//                                     relay_server: relay_server.clone(),
-//                                     ..Default::default()
-//                                 });
-//                                 sock.send(&msg_out, addr.clone()).await.ok();
-//                                 saved_stream = Some(stream);
-//                             }
-//                         },
-//                         Some(rendezvous_message::Union::RelayResponse(_)) => {
-//                             info!{peer_addr = %addr, "recived RelayResponse"};
-//                             let mut msg_out = RendezvousMessage::new();
-//                             msg_out.set_relay_response(RelayResponse {
-//                                 relay_server: relay_server.clone(),
-//                                 ..Default::default()
-//                             });
-
-//                             if let Some(mut stream) = saved_stream.take() {
-//                                 stream.send(&msg_out).await.ok();
-//                                 if let Ok((stream_a, _)) = rlistener.accept().await {
-//                                     let mut stream_a = FramedStream::from(stream_a, addr);
-//                                     stream_a.next_timeout(3000).await;
-//                                     if let Ok((stream_b, _)) = rlistener.accept().await {
-//                                         let mut stream_b = FramedStream::from(stream_b, addr);
-//                                         stream_b.next_timeout(3000).await;
-//                                         relay(stream_a, stream_b, &mut sock, &mut id_map).await;
-//                                     }
-//                                 }
-//                             }
-//                         }
-//                         _ => {}
-//                     }
-//                 }
-//             }
-//         }
-//     }
-// }
+fn open_key_exchange(ex: &KeyExchange, our_sk: &box_::SecretKey) -> Option<secretbox::Key> {
+    let [their_pk, sealed_key] = ex.keys.as_slice() else {
+        return None;
+    };
+
+    let their_pk = box_::PublicKey::from_slice(their_pk)?;
+    let nonce = box_::Nonce([0u8; box_::NONCEBYTES]);
+    let key = box_::open(sealed_key, &nonce, &their_pk, our_sk).ok()?;
+
+    secretbox::Key::from_slice(&key)
+}
+
+/// Binding `::` only accepts IPv4 as well if the OS leaves IPV6_V6ONLY off,
+/// `listen_any` clears it explicitly. UDP sockets from `FramedSocket` already
+/// do the same.
+pub async fn listen(addr: SocketAddr) -> TangoResult<TcpListener> {
+    match addr.ip() {
+        IpAddr::V6(ip) if ip.is_unspecified() => Ok(listen_any(addr.port()).await?),
+        _ => Ok(new_listener(addr, false).await?),
+    }
+}
+
+/// Variant name of `msg`, used to label protocol errors.
+pub fn message_kind(msg: &RendezvousMessage) -> &'static str {
+    use rendezvous_message::Union;
+
+    match &msg.union {
+        Some(Union::RegisterPeer(_)) => "RegisterPeer",
+        Some(Union::RegisterPeerResponse(_)) => "RegisterPeerResponse",
+        Some(Union::PunchHoleRequest(_)) => "PunchHoleRequest",
+        Some(Union::PunchHole(_)) => "PunchHole",
+        Some(Union::PunchHoleSent(_)) => "PunchHoleSent",
+        Some(Union::PunchHoleResponse(_)) => "PunchHoleResponse",
+        Some(Union::FetchLocalAddr(_)) => "FetchLocalAddr",
+        Some(Union::LocalAddr(_)) => "LocalAddr",
+        Some(Union::ConfigureUpdate(_)) => "ConfigureUpdate",
+        Some(Union::RegisterPk(_)) => "RegisterPk",
+        Some(Union::RegisterPkResponse(_)) => "RegisterPkResponse",
+        Some(Union::SoftwareUpdate(_)) => "SoftwareUpdate",
+        Some(Union::RequestRelay(_)) => "RequestRelay",
+        Some(Union::RelayResponse(_)) => "RelayResponse",
+        Some(Union::TestNatRequest(_)) => "TestNatRequest",
+        Some(Union::TestNatResponse(_)) => "TestNatResponse",
+        Some(Union::PeerDiscovery(_)) => "PeerDiscovery",
+        Some(Union::OnlineRequest(_)) => "OnlineRequest",
+        Some(Union::OnlineResponse(_)) => "OnlineResponse",
+        Some(Union::KeyExchange(_)) => "KeyExchange",
+        Some(Union::Hc(_)) => "Hc",
+        #[allow(unreachable_patterns)]
+        Some(_) => "Unknown",
+        None => "Empty",
+    }
+}